@@ -0,0 +1,97 @@
+use axum::{
+    extract::{Request, State},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use crate::config::ApiKeyConfig;
+use crate::error::ApiError;
+use crate::server::ServerState;
+
+const KEY_PREFIX: &str = "gp-";
+/// Characters of a key kept in the config to identify it in listings.
+const DISPLAY_PREFIX_LEN: usize = 8;
+
+/// Creates a new random key. The key itself is returned only here; the
+/// config entry holds its hash.
+pub fn generate(label: &str) -> (String, ApiKeyConfig) {
+    let secret: [u8; 32] = rand::random();
+    let key = format!("{}{}", KEY_PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret));
+    let entry = ApiKeyConfig {
+        id: format!("{:08x}", rand::random::<u32>()),
+        label: label.to_string(),
+        hash: hash_key(&key),
+        prefix: key[..DISPLAY_PREFIX_LEN].to_string(),
+        created_at: chrono::Utc::now().timestamp().max(0) as u64,
+    };
+    (key, entry)
+}
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The configured key matching `key`, if any.
+pub fn find<'a>(keys: &'a [ApiKeyConfig], key: &str) -> Option<&'a ApiKeyConfig> {
+    let hash = hash_key(key);
+    keys.iter().find(|k| constant_time_eq(k.hash.as_bytes(), hash.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The key sent as `Authorization: Bearer <key>` or `x-api-key: <key>`.
+fn presented_key(req: &Request) -> Option<&str> {
+    let headers = req.headers();
+    headers.get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer ").or_else(|| v.strip_prefix("bearer ")))
+        .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+}
+
+/// Rejects requests without a configured key. Does nothing when no keys are
+/// configured.
+pub async fn require_api_key(State(state): State<Arc<ServerState>>, req: Request, next: Next) -> Response {
+    let keys = &state.config.api_keys;
+    if keys.is_empty() {
+        return next.run(req).await;
+    }
+
+    let Some(key) = presented_key(&req) else {
+        return ApiError::invalid_api_key(
+            "You didn't provide an API key. Send it as 'Authorization: Bearer <key>' or in the 'x-api-key' header.",
+        ).into_response();
+    };
+    match find(keys, key) {
+        Some(entry) => {
+            log::debug!("Request authorized with API key '{}'", entry.label);
+            next.run(req).await
+        }
+        None => ApiError::invalid_api_key("Incorrect API key provided.").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_keys_match_only_themselves() {
+        let (key, entry) = generate("ci");
+        let (other_key, other) = generate("laptop");
+        let keys = vec![entry, other];
+
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(keys[0].prefix, key[..DISPLAY_PREFIX_LEN]);
+        assert_ne!(keys[0].hash, key);
+        assert_eq!(find(&keys, &key).map(|k| k.label.as_str()), Some("ci"));
+        assert_eq!(find(&keys, &other_key).map(|k| k.label.as_str()), Some("laptop"));
+        assert!(find(&keys, "gp-wrong").is_none());
+    }
+}
@@ -0,0 +1,450 @@
+use anyhow::{Result, anyhow};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use crate::config::{update_config, AuthConfig, Config, RotationConfig, RotationStrategy};
+use crate::error::ApiError;
+use crate::gemini_cli;
+use crate::oauth::{application_default_credentials, CredentialProvider};
+
+/// Tokens are refreshed this long before they expire.
+const REFRESH_MARGIN_SECS: u64 = 300;
+/// Delay before the background task tries again after a failed refresh.
+const REFRESH_RETRY_SECS: u64 = 30;
+/// Shortest cooldown after a 429, even when upstream says to retry at once.
+const MIN_COOLDOWN: Duration = Duration::from_secs(1);
+/// How often files that accounts sync from are checked for changes.
+const SYNC_INTERVAL_SECS: u64 = 30;
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+struct RateLimit {
+    limited_at: Instant,
+    until: Instant,
+}
+
+/// One Google account of the pool. Keeps its access token in memory,
+/// refreshes it before it expires and writes refreshed tokens back to the
+/// config file. The lock is held while refreshing, so concurrent requests
+/// wait for a single refresh instead of starting their own.
+pub struct Account {
+    label: String,
+    sync_from: Option<PathBuf>,
+    /// Configured override of the token endpoint.
+    token_url: Option<String>,
+    /// The shared upstream client, used for token exchanges.
+    http: reqwest::Client,
+    auth: Mutex<AuthConfig>,
+    /// Last 429 per model; the account is skipped for that model until
+    /// `until` has passed.
+    limits: std::sync::Mutex<HashMap<String, RateLimit>>,
+    /// Set when the token could not be refreshed; the account is skipped
+    /// for every model until then.
+    unusable_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl Account {
+    fn new(auth: AuthConfig, token_url: Option<String>, http: reqwest::Client) -> Self {
+        Account {
+            label: auth.label().to_string(),
+            sync_from: auth.sync_from.clone(),
+            token_url,
+            http,
+            auth: Mutex::new(auth),
+            limits: std::sync::Mutex::new(HashMap::new()),
+            unusable_until: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// A valid access token, refreshing it first if it is about to expire.
+    pub async fn access_token(&self) -> Result<String> {
+        let mut auth = self.auth.lock().await;
+        if now_secs() + REFRESH_MARGIN_SECS >= auth.expires_at {
+            refresh(&self.http, &mut auth, self.token_url.as_deref()).await?;
+        }
+        Ok(auth.access_token.clone())
+    }
+
+    /// Refreshes after upstream rejected `rejected_token`. If another request
+    /// already replaced that token, the current one is returned instead.
+    pub async fn force_refresh(&self, rejected_token: &str) -> Result<String> {
+        let mut auth = self.auth.lock().await;
+        if auth.access_token == rejected_token {
+            refresh(&self.http, &mut auth, self.token_url.as_deref()).await?;
+        }
+        Ok(auth.access_token.clone())
+    }
+
+    /// The configured project id, or `None` if it still has to be detected.
+    pub async fn project_id(&self) -> Option<String> {
+        self.auth.lock().await.project_id.clone().filter(|p| p != "default")
+    }
+
+    /// Remembers a detected project id and persists it.
+    pub async fn set_project_id(&self, project_id: &str) {
+        let mut auth = self.auth.lock().await;
+        auth.project_id = Some(project_id.to_string());
+        persist(&auth, |saved| saved.project_id = Some(project_id.to_string()));
+    }
+
+    /// Adopts the tokens in the gemini-cli file the account was imported
+    /// from, if gemini-cli has replaced or refreshed them.
+    async fn sync_from_file(&self, path: &Path) -> Result<()> {
+        let imported = gemini_cli::load(path)?;
+        let mut auth = self.auth.lock().await;
+        if imported.refresh_token == auth.refresh_token && imported.expires_at <= auth.expires_at {
+            return Ok(());
+        }
+
+        log::info!("Adopting updated gemini-cli tokens for {}", self.label);
+        let previous = auth.clone();
+        auth.access_token = imported.access_token;
+        auth.refresh_token = imported.refresh_token;
+        auth.expires_at = imported.expires_at;
+        let updated = auth.clone();
+        persist(&previous, |saved| {
+            saved.access_token = updated.access_token;
+            saved.refresh_token = updated.refresh_token;
+            saved.expires_at = updated.expires_at;
+        });
+        Ok(())
+    }
+
+    async fn expires_at(&self) -> u64 {
+        self.auth.lock().await.expires_at
+    }
+
+    /// Whether the current access token is unexpired, without refreshing it.
+    pub async fn has_valid_token(&self) -> bool {
+        now_secs() < self.expires_at().await
+    }
+
+    fn limits(&self) -> std::sync::MutexGuard<'_, HashMap<String, RateLimit>> {
+        self.limits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn unusable_until(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.unusable_until.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn cooldown_remaining(&self, model: &str, now: Instant) -> Option<Duration> {
+        let limited = self.limits().get(model).map(|limit| limit.until);
+        let unusable = *self.unusable_until();
+        limited.max(unusable)
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn last_limited(&self, model: &str) -> Option<Instant> {
+        self.limits().get(model).map(|limit| limit.limited_at)
+    }
+}
+
+/// Owns the server's credentials: a pool of accounts that requests are
+/// spread across, skipping accounts that were recently rate limited.
+pub struct AuthManager {
+    accounts: Vec<Arc<Account>>,
+    rotation: RotationConfig,
+    next: AtomicUsize,
+}
+
+impl AuthManager {
+    /// The configured accounts, plus the application default credentials
+    /// when `GOOGLE_APPLICATION_CREDENTIALS` names a file no account uses.
+    pub fn from_config(config: &Config, http: reqwest::Client) -> Self {
+        let mut accounts = config.accounts.clone();
+        if let Some(path) = application_default_credentials() {
+            if !accounts.iter().any(|a| a.credentials_file.as_ref() == Some(&path)) {
+                accounts.push(application_default_account(path, config.token_url.as_deref()));
+            }
+        }
+
+        AuthManager {
+            accounts: accounts.into_iter()
+                .map(|auth| Arc::new(Account::new(auth, config.token_url.clone(), http.clone())))
+                .collect(),
+            rotation: config.rotation.clone(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn accounts(&self) -> &[Arc<Account>] {
+        &self.accounts
+    }
+
+    /// Refreshes expired tokens up front. Accounts that cannot be refreshed
+    /// are only reported, unless none is usable.
+    pub async fn check_accounts(&self) -> Result<()> {
+        let mut usable = 0;
+        for account in &self.accounts {
+            match account.access_token().await {
+                Ok(_) => usable += 1,
+                Err(e) => log::warn!("Account {} is unusable: {}", account.label(), e),
+            }
+        }
+        if usable == 0 {
+            return Err(anyhow!("No usable account. Run 'gemini-proxy login' or set GOOGLE_APPLICATION_CREDENTIALS."));
+        }
+        Ok(())
+    }
+
+    /// Picks the account to send the next request for `model` with. Fails
+    /// with a 429 when every account is cooling down for it.
+    pub fn next_account(&self, model: &str) -> Result<Arc<Account>> {
+        if self.accounts.is_empty() {
+            return Err(anyhow!("No authentication found. Run 'gemini-proxy login' first."));
+        }
+
+        let now = Instant::now();
+        let n = self.accounts.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut available = (0..n)
+            .map(|i| &self.accounts[(start + i) % n])
+            .filter(|a| a.cooldown_remaining(model, now).is_none());
+        let chosen = match self.rotation.strategy {
+            RotationStrategy::RoundRobin => available.next(),
+            // `None` sorts first, so accounts never limited are preferred.
+            RotationStrategy::LeastRecentlyLimited => available.min_by_key(|a| a.last_limited(model)),
+        };
+
+        match chosen {
+            Some(account) => Ok(Arc::clone(account)),
+            None => {
+                let retry_after = self.accounts.iter()
+                    .filter_map(|a| a.cooldown_remaining(model, now))
+                    .min();
+                Err(ApiError::rate_limited(
+                    format!("All {} account(s) are rate limited for {}", n, model),
+                    retry_after,
+                ).into())
+            }
+        }
+    }
+
+    /// Number of accounts not cooling down for `model`.
+    pub fn available(&self, model: &str) -> usize {
+        let now = Instant::now();
+        self.accounts.iter().filter(|a| a.cooldown_remaining(model, now).is_none()).count()
+    }
+
+    /// Takes `account` out of rotation for `model` after a 429, for the
+    /// delay upstream asked for or the configured cooldown. A zero delay
+    /// still counts as a second, so the account really leaves the rotation.
+    pub fn mark_rate_limited(&self, account: &Account, model: &str, retry_after: Option<Duration>) {
+        let cooldown = retry_after
+            .unwrap_or(Duration::from_secs(self.rotation.cooldown_secs))
+            .max(MIN_COOLDOWN);
+        log::warn!("Account {} is rate limited for {}; cooling down for {:?}", account.label(), model, cooldown);
+        let now = Instant::now();
+        account.limits().insert(model.to_string(), RateLimit { limited_at: now, until: now + cooldown });
+    }
+
+    /// Takes `account` out of rotation for every model for the configured
+    /// cooldown, after its token could not be refreshed.
+    pub fn mark_unusable(&self, account: &Account, error: &anyhow::Error) {
+        let cooldown = Duration::from_secs(self.rotation.cooldown_secs).max(MIN_COOLDOWN);
+        log::warn!("Account {} is unusable ({}); skipping it for {:?}", account.label(), error, cooldown);
+        *account.unusable_until() = Some(Instant::now() + cooldown);
+    }
+
+    /// Rotation state of every account, for the health endpoint.
+    pub fn status(&self) -> Vec<Value> {
+        let now = Instant::now();
+        self.accounts.iter().map(|account| {
+            let cooldowns: HashMap<String, u64> = account.limits().iter()
+                .filter(|(_, limit)| limit.until > now)
+                .map(|(model, limit)| (model.clone(), limit.until.duration_since(now).as_secs_f64().ceil() as u64))
+                .collect();
+            let unusable_secs = account.unusable_until()
+                .filter(|until| *until > now)
+                .map(|until| until.duration_since(now).as_secs_f64().ceil() as u64);
+            json!({ "account": account.label(), "cooldownSecs": cooldowns, "unusableSecs": unusable_secs })
+        }).collect()
+    }
+
+    /// Keeps every account's token fresh in the background so requests
+    /// rarely have to wait for a refresh, and picks up new gemini-cli tokens
+    /// for accounts that sync from it.
+    pub fn spawn_refresh_task(&self) {
+        for account in &self.accounts {
+            if let Some(path) = account.sync_from.clone() {
+                let account = Arc::clone(account);
+                tokio::spawn(async move {
+                    let mut last_modified = None;
+                    loop {
+                        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                        if modified.is_some() && modified != last_modified {
+                            match account.sync_from_file(&path).await {
+                                Ok(()) => last_modified = modified,
+                                Err(e) => log::warn!("Syncing {} from {:?} failed: {}", account.label(), path, e),
+                            }
+                        }
+                        tokio::time::sleep(Duration::from_secs(SYNC_INTERVAL_SECS)).await;
+                    }
+                });
+            }
+
+            let account = Arc::clone(account);
+            tokio::spawn(async move {
+                loop {
+                    let refresh_at = account.expires_at().await.saturating_sub(REFRESH_MARGIN_SECS);
+                    tokio::time::sleep(Duration::from_secs(refresh_at.saturating_sub(now_secs()))).await;
+
+                    if let Err(e) = account.access_token().await {
+                        log::warn!("Background token refresh for {} failed: {}", account.label(), e);
+                        tokio::time::sleep(Duration::from_secs(REFRESH_RETRY_SECS)).await;
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// An account for the application default credentials at `path`. It is not
+/// saved; its project comes from `GOOGLE_CLOUD_PROJECT` or is detected.
+fn application_default_account(path: PathBuf, token_url: Option<&str>) -> AuthConfig {
+    let email = CredentialProvider::from_file(&path, token_url).ok()
+        .and_then(|provider| provider.email().map(|e| e.to_string()));
+    AuthConfig {
+        email,
+        project_id: std::env::var("GOOGLE_CLOUD_PROJECT").ok().filter(|p| !p.is_empty()),
+        credentials_file: Some(path),
+        ..Default::default()
+    }
+}
+
+async fn refresh(http: &reqwest::Client, auth: &mut AuthConfig, token_url: Option<&str>) -> Result<()> {
+    log::info!("Refreshing access token for {}", auth.label());
+    let provider = CredentialProvider::for_account(auth, token_url)?;
+    let (access_token, expires_at) = provider.fetch_token(http).await?;
+    auth.access_token = access_token;
+    auth.expires_at = expires_at;
+    persist(auth, |saved| {
+        saved.access_token = auth.access_token.clone();
+        saved.expires_at = auth.expires_at;
+    });
+    Ok(())
+}
+
+/// Applies `update` to the saved copy of `auth`, if it is saved at all. The
+/// file is re-read so accounts and other changes made since startup are kept.
+/// The in-memory state is what requests use, so a failed write is only
+/// logged.
+fn persist(auth: &AuthConfig, update: impl FnOnce(&mut AuthConfig)) {
+    let result = update_config(|config| {
+        let saved = config.accounts.iter_mut()
+            .find(|a| a.refresh_token == auth.refresh_token && a.credentials_file == auth.credentials_file);
+        match saved {
+            Some(saved) => {
+                update(saved);
+                true
+            }
+            None => false,
+        }
+    });
+    if let Err(e) = result {
+        log::warn!("Could not save updated credentials of {}: {:#}", auth.label(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: RotationStrategy) -> AuthManager {
+        let account = |email: &str| AuthConfig { email: Some(email.to_string()), ..Default::default() };
+        AuthManager::from_config(&Config {
+            accounts: vec![account("a"), account("b"), account("c")],
+            rotation: RotationConfig { strategy, cooldown_secs: 60 },
+            ..Default::default()
+        }, reqwest::Client::new())
+    }
+
+    fn pick(pool: &AuthManager, model: &str) -> String {
+        pool.next_account(model).unwrap().label().to_string()
+    }
+
+    #[test]
+    fn round_robin_skips_accounts_cooling_down_for_the_model() {
+        let pool = pool(RotationStrategy::RoundRobin);
+        assert_eq!([pick(&pool, "m"), pick(&pool, "m"), pick(&pool, "m")], ["a", "b", "c"]);
+
+        pool.mark_rate_limited(&pool.accounts()[1], "m", None);
+        assert_eq!([pick(&pool, "m"), pick(&pool, "m"), pick(&pool, "m")], ["a", "c", "c"]);
+        assert_eq!(pool.available("m"), 2);
+        assert_eq!(pool.available("other"), 3);
+    }
+
+    /// Records a 429 `ago` in the past whose cooldown is already over.
+    fn limited_before(account: &Account, model: &str, ago: Duration) {
+        let now = Instant::now();
+        account.limits().insert(model.to_string(), RateLimit { limited_at: now - ago, until: now });
+    }
+
+    #[test]
+    fn least_recently_limited_prefers_accounts_never_limited() {
+        let pool = pool(RotationStrategy::LeastRecentlyLimited);
+        limited_before(&pool.accounts()[0], "m", Duration::from_secs(3));
+        limited_before(&pool.accounts()[1], "m", Duration::from_secs(2));
+        assert_eq!(pick(&pool, "m"), "c");
+
+        limited_before(&pool.accounts()[2], "m", Duration::from_secs(1));
+        assert_eq!(pick(&pool, "m"), "a");
+    }
+
+    #[tokio::test]
+    async fn refresh_succeeds_when_the_config_cannot_be_written() {
+        let app = axum::Router::new().fallback(|| async {
+            axum::Json(json!({ "access_token": "fresh", "expires_in": 3600, "token_type": "Bearer" }))
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_url = format!("http://{}/token", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let saved = AuthConfig { refresh_token: "r".to_string(), email: Some("a".to_string()), ..Default::default() };
+        let config = Config { accounts: vec![saved], token_url: Some(token_url), ..Default::default() };
+        // A directory in place of the temporary file makes every write fail.
+        let dir = std::env::temp_dir().join(format!("auth-test-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join(format!("config.json.{}.tmp", std::process::id()))).unwrap();
+        std::fs::write(dir.join("config.json"), serde_json::to_string(&config).unwrap()).unwrap();
+        std::env::set_var("GEMINI_PROXY_CONFIG_DIR", &dir);
+
+        let pool = AuthManager::from_config(&config, reqwest::Client::new());
+        let token = pool.accounts()[0].access_token().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(token.unwrap(), "fresh");
+    }
+
+    #[test]
+    fn zero_retry_after_still_takes_the_account_out_of_rotation() {
+        let pool = pool(RotationStrategy::RoundRobin);
+        pool.mark_rate_limited(&pool.accounts()[0], "m", Some(Duration::ZERO));
+        assert_eq!(pool.available("m"), 2);
+    }
+
+    #[test]
+    fn fails_with_retry_after_when_every_account_is_limited() {
+        let pool = pool(RotationStrategy::RoundRobin);
+        for (account, secs) in pool.accounts().iter().zip([30, 10, 20]) {
+            pool.mark_rate_limited(account, "m", Some(Duration::from_secs(secs)));
+        }
+
+        let Err(error) = pool.next_account("m") else { panic!("an account was available") };
+        let error = error.downcast::<ApiError>().unwrap();
+        assert_eq!(error.status.as_u16(), 429);
+        assert!(error.retry_after.is_some_and(|d| d <= Duration::from_secs(10) && d > Duration::from_secs(9)));
+    }
+}
@@ -1,24 +1,219 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::fs;
+use std::sync::{Mutex, MutexGuard};
 use anyhow::{Result, Context};
+use crate::constants::{GEMINI_CODE_ASSIST_ENDPOINT, GOOGLE_TOKEN_URL};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct AuthConfig {
     #[serde(rename = "accessToken")]
     pub access_token: String,
-    #[serde(rename = "refreshToken")]
+    /// Empty for accounts backed by a credentials file.
+    #[serde(rename = "refreshToken", default)]
     pub refresh_token: String,
     #[serde(rename = "expiresAt")]
     pub expires_at: u64,
     pub email: Option<String>,
+    /// Code Assist project used with this account; detected when unset.
+    #[serde(rename = "projectId", default)]
+    pub project_id: Option<String>,
+    /// gemini-cli credentials file whose tokens the server keeps adopting.
+    #[serde(rename = "syncFrom", default)]
+    pub sync_from: Option<PathBuf>,
+    /// Service account key or `authorized_user` file that tokens are
+    /// obtained with, instead of `refreshToken`.
+    #[serde(rename = "credentialsFile", default)]
+    pub credentials_file: Option<PathBuf>,
+}
+
+impl AuthConfig {
+    /// How the account is shown in logs and status output.
+    pub fn label(&self) -> &str {
+        match (&self.email, &self.credentials_file) {
+            (Some(email), _) => email,
+            (None, Some(path)) => path.to_str().unwrap_or("credentials file"),
+            (None, None) => "unknown account",
+        }
+    }
+}
+
+/// How system/developer messages that appear after the conversation has
+/// started are handled. Leading system messages always become the
+/// `systemInstruction`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemMessagePolicy {
+    /// Append them to the `systemInstruction`.
+    #[default]
+    Hoist,
+    /// Send them as user turns at their original position.
+    Inline,
+    /// Fail the request with a 400 error.
+    Reject,
+}
+
+/// How prompts that Gemini blocks outright are reported to the client.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockedPromptPolicy {
+    /// Return a completion whose message carries a `refusal`.
+    #[default]
+    Refusal,
+    /// Fail the request with a 400 `content_filter` error.
+    Error,
+}
+
+/// Retry policy for upstream calls that fail with 429/503 or
+/// `RESOURCE_EXHAUSTED`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total attempts, including the first one.
+    #[serde(rename = "maxAttempts")]
+    pub max_attempts: u32,
+    #[serde(rename = "initialBackoffMs")]
+    pub initial_backoff_ms: u64,
+    #[serde(rename = "maxBackoffMs")]
+    pub max_backoff_ms: u64,
+    /// No retry is started if it would end after this many seconds.
+    #[serde(rename = "deadlineSecs")]
+    pub deadline_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            deadline_secs: 60,
+        }
+    }
+}
+
+/// How requests are spread across accounts.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RotationStrategy {
+    /// Take turns.
+    #[default]
+    RoundRobin,
+    /// Prefer the account whose last 429 for the model is the oldest.
+    LeastRecentlyLimited,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RotationConfig {
+    pub strategy: RotationStrategy,
+    /// How long an account is skipped for a model after a 429, unless
+    /// upstream says how long to wait.
+    #[serde(rename = "cooldownSecs")]
+    pub cooldown_secs: u64,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        RotationConfig {
+            strategy: RotationStrategy::RoundRobin,
+            cooldown_secs: 60,
+        }
+    }
+}
+
+/// Settings for the HTTP client used for all upstream calls.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// Code Assist endpoint; override to point at a local stand-in.
+    #[serde(rename = "baseUrl")]
+    pub base_url: String,
+    #[serde(rename = "connectTimeoutSecs")]
+    pub connect_timeout_secs: u64,
+    /// Longest pause between two reads of a response body.
+    #[serde(rename = "readTimeoutSecs")]
+    pub read_timeout_secs: u64,
+    /// Limit for a whole request including the response body. Streamed
+    /// completions are exempt and only bounded by `read_timeout_secs`.
+    #[serde(rename = "requestTimeoutSecs")]
+    pub request_timeout_secs: u64,
+    /// Outbound proxy URL. When unset, `HTTPS_PROXY`/`HTTP_PROXY`/`NO_PROXY`
+    /// from the environment apply.
+    pub proxy: Option<String>,
+    /// Comma-separated hosts that bypass `proxy`.
+    #[serde(rename = "noProxy")]
+    pub no_proxy: Option<String>,
+    /// PEM files with extra CA certificates to trust.
+    #[serde(rename = "caCertificates")]
+    pub ca_certificates: Vec<PathBuf>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            base_url: GEMINI_CODE_ASSIST_ENDPOINT.to_string(),
+            connect_timeout_secs: 10,
+            read_timeout_secs: 120,
+            request_timeout_secs: 600,
+            proxy: None,
+            no_proxy: None,
+            ca_certificates: Vec::new(),
+        }
+    }
+}
+
+/// A key accepted by the proxy's own API. Only a hash of the key is kept.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    pub id: String,
+    pub label: String,
+    /// Hex-encoded SHA-256 of the key.
+    pub hash: String,
+    /// The first characters of the key, to help tell keys apart.
+    pub prefix: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Config {
+    /// Google accounts requests are spread across.
+    #[serde(default)]
+    pub accounts: Vec<AuthConfig>,
+    #[serde(default)]
+    pub rotation: RotationConfig,
+    /// Single-account layout of older versions, moved into `accounts` on load.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub auth: Option<AuthConfig>,
-    #[serde(rename = "projectId")]
+    #[serde(rename = "projectId", skip_serializing_if = "Option::is_none")]
     pub project_id: Option<String>,
+    #[serde(rename = "systemMessages", default)]
+    pub system_messages: SystemMessagePolicy,
+    #[serde(rename = "blockedPrompts", default)]
+    pub blocked_prompts: BlockedPromptPolicy,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Models to try, in order, when a model keeps failing with quota or
+    /// server errors, keyed by the requested model.
+    #[serde(rename = "modelFallbacks", default)]
+    pub model_fallbacks: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    /// Overrides the Google token endpoint for every token exchange, e.g. to
+    /// test against a local stand-in.
+    #[serde(rename = "tokenUrl", default)]
+    pub token_url: Option<String>,
+    /// Keys clients must present. When empty, the API is open.
+    #[serde(rename = "apiKeys", default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+}
+
+impl Config {
+    pub fn token_url(&self) -> &str {
+        self.token_url.as_deref().unwrap_or(GOOGLE_TOKEN_URL)
+    }
 }
 
 pub fn get_config_dir() -> PathBuf {
@@ -46,11 +241,54 @@ pub fn load_config() -> Result<Config> {
     let content = fs::read_to_string(&path)
         .with_context(|| format!("Failed to read config file at {:?}", path))?;
     
-    serde_json::from_str(&content)
-        .with_context(|| "Failed to parse config JSON")
+    let mut config: Config = serde_json::from_str(&content)
+        .with_context(|| "Failed to parse config JSON")?;
+    migrate_single_account(&mut config);
+    Ok(config)
+}
+
+fn migrate_single_account(config: &mut Config) {
+    if let Some(auth) = config.auth.take() {
+        config.accounts.insert(0, auth);
+    }
+    if config.accounts.is_empty() {
+        return;
+    }
+    if let Some(project_id) = config.project_id.take() {
+        for account in config.accounts.iter_mut().filter(|a| a.project_id.is_none()) {
+            account.project_id = Some(project_id.clone());
+        }
+    }
+}
+
+/// Serializes config writes within the process, so concurrent updates
+/// (e.g. token refreshes of several accounts) do not lose each other's
+/// changes.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_config() -> MutexGuard<'static, ()> {
+    CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 pub fn save_config(config: &Config) -> Result<()> {
+    let _guard = lock_config();
+    write_config(config)
+}
+
+/// Applies `update` to the config on disk and saves it if `update` returns
+/// true. Reading and writing happen under the config lock.
+pub fn update_config(update: impl FnOnce(&mut Config) -> bool) -> Result<()> {
+    let _guard = lock_config();
+    let mut config = load_config()?;
+    if update(&mut config) {
+        write_config(&config)?;
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file and renames it over the config, so readers
+/// never see a partially written file.
+fn write_config(config: &Config) -> Result<()> {
     let dir = get_config_dir();
     if !dir.exists() {
         fs::create_dir_all(&dir)
@@ -61,8 +299,11 @@ pub fn save_config(config: &Config) -> Result<()> {
     let content = serde_json::to_string_pretty(config)
         .with_context(|| "Failed to serialize config to JSON")?;
     
-    fs::write(&path, content)
-        .with_context(|| format!("Failed to write config file at {:?}", path))?;
+    let temp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&temp, content)
+        .with_context(|| format!("Failed to write config file at {:?}", temp))?;
+    fs::rename(&temp, &path)
+        .with_context(|| format!("Failed to replace config file at {:?}", path))?;
     
     Ok(())
 }
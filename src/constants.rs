@@ -3,7 +3,10 @@ pub const GEMINI_SCOPES: &[&str] = &[
     "https://www.googleapis.com/auth/userinfo.email",
     "https://www.googleapis.com/auth/userinfo.profile",
 ];
-pub const GEMINI_REDIRECT_URI: &str = "http://localhost:8085/oauth2callback";
+pub const OAUTH_CALLBACK_PATH: &str = "/oauth2callback";
+/// Preferred port of the local OAuth callback server.
+pub const OAUTH_CALLBACK_PORT: u16 = 8085;
+pub const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
 pub const GEMINI_CODE_ASSIST_ENDPOINT: &str = "https://cloudcode-pa.googleapis.com";
 
 pub const CODE_ASSIST_HEADERS: &[(&str, &str)] = &[
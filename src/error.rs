@@ -0,0 +1,264 @@
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+
+/// Code of errors caused by Google rejecting the proxy's own credentials.
+pub const UPSTREAM_AUTHENTICATION_ERROR: &str = "upstream_authentication_error";
+
+/// An error that is reported to the client as an OpenAI-style error object.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    pub error_type: String,
+    pub code: Option<String>,
+    pub param: Option<String>,
+    /// Sent to the client as a `Retry-After` header.
+    pub retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn invalid_request(message: impl Into<String>, param: Option<&str>) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            error_type: "invalid_request_error".to_string(),
+            code: None,
+            param: param.map(|p| p.to_string()),
+            retry_after: None,
+        }
+    }
+
+    pub fn content_filter(message: impl Into<String>) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            error_type: "invalid_request_error".to_string(),
+            code: Some("content_filter".to_string()),
+            param: Some("messages".to_string()),
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid_api_key(message: impl Into<String>) -> Self {
+        ApiError {
+            status: StatusCode::UNAUTHORIZED,
+            message: message.into(),
+            error_type: "invalid_request_error".to_string(),
+            code: Some("invalid_api_key".to_string()),
+            param: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn rate_limited(message: impl Into<String>, retry_after: Option<Duration>) -> Self {
+        ApiError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            message: message.into(),
+            error_type: "rate_limit_error".to_string(),
+            code: Some("rate_limit_exceeded".to_string()),
+            param: None,
+            retry_after,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.into(),
+            error_type: "server_error".to_string(),
+            code: None,
+            param: None,
+            retry_after: None,
+        }
+    }
+
+    /// Model output that does not satisfy a strict `json_schema`.
+    pub fn response_schema_mismatch(message: impl Into<String>) -> Self {
+        ApiError {
+            code: Some("response_schema_mismatch".to_string()),
+            ..ApiError::internal(format!("Model output did not match the requested json_schema: {}", message.into()))
+        }
+    }
+
+    /// Translates an error response from Code Assist, normally a
+    /// `google.rpc.Status`, into the matching OpenAI error.
+    pub fn from_upstream(status: u16, body: &str) -> Self {
+        let parsed: Option<Value> = serde_json::from_str(body).ok();
+        // Streaming endpoints wrap the status in an array.
+        let error = parsed.as_ref()
+            .map(|v| v.get(0).unwrap_or(v))
+            .and_then(|v| v.get("error"));
+
+        let rpc_status = error.and_then(|e| e["status"].as_str()).unwrap_or("");
+        let message = error
+            .and_then(|e| e["message"].as_str())
+            .map(|m| m.to_string())
+            .unwrap_or_else(|| {
+                let body = body.trim();
+                if body.is_empty() { format!("Upstream returned HTTP {}", status) } else { body.to_string() }
+            });
+        let retry_after = error
+            .and_then(|e| e["details"].as_array())
+            .and_then(|details| details.iter().find(|d| {
+                d["@type"].as_str().is_some_and(|t| t.ends_with("google.rpc.RetryInfo"))
+            }))
+            .and_then(|d| d["retryDelay"].as_str())
+            .and_then(parse_duration);
+
+        let (status, error_type, code) = match status {
+            400 => (StatusCode::BAD_REQUEST, "invalid_request_error", None),
+            // The proxy's Google credentials were rejected, not the client's key.
+            401 => (StatusCode::BAD_GATEWAY, "authentication_error", Some(UPSTREAM_AUTHENTICATION_ERROR)),
+            403 => (StatusCode::FORBIDDEN, "permission_error", None),
+            404 => (StatusCode::NOT_FOUND, "not_found_error", Some("model_not_found")),
+            429 if rpc_status == "RESOURCE_EXHAUSTED" => (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", Some("insufficient_quota")),
+            429 => (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", Some("rate_limit_exceeded")),
+            503 => (StatusCode::SERVICE_UNAVAILABLE, "server_error", Some("service_unavailable")),
+            s if (400..500).contains(&s) => (StatusCode::BAD_REQUEST, "invalid_request_error", None),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "server_error", None),
+        };
+
+        ApiError {
+            status,
+            message,
+            error_type: error_type.to_string(),
+            code: code.map(|c| c.to_string()).or_else(|| {
+                (!rpc_status.is_empty()).then(|| rpc_status.to_ascii_lowercase())
+            }),
+            param: None,
+            retry_after,
+        }
+    }
+
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "message": self.message,
+                "type": self.error_type,
+                "code": self.code,
+                "param": self.param,
+            }
+        })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<ApiError>() {
+            Ok(api_err) => api_err,
+            Err(err) => ApiError::internal(err.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(self.body())).into_response();
+        if let Some(retry_after) = self.retry_after {
+            // Retry-After only takes whole seconds; round up.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            if let Ok(value) = HeaderValue::from_str(&secs.to_string()) {
+                response.headers_mut().insert(RETRY_AFTER, value);
+            }
+        }
+        response
+    }
+}
+
+/// Parses a protobuf JSON duration such as `"3.5s"`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let secs: f64 = value.trim().strip_suffix('s')?.parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_body(code: u16, status: &str) -> String {
+        json!({ "error": { "code": code, "message": "upstream says no", "status": status } }).to_string()
+    }
+
+    #[test]
+    fn maps_upstream_statuses() {
+        let cases = [
+            (400, "INVALID_ARGUMENT", 400, "invalid_request_error", "invalid_argument"),
+            (401, "UNAUTHENTICATED", 502, "authentication_error", UPSTREAM_AUTHENTICATION_ERROR),
+            (403, "PERMISSION_DENIED", 403, "permission_error", "permission_denied"),
+            (404, "NOT_FOUND", 404, "not_found_error", "model_not_found"),
+            (409, "ABORTED", 400, "invalid_request_error", "aborted"),
+            (429, "UNAVAILABLE", 429, "rate_limit_error", "rate_limit_exceeded"),
+            (429, "RESOURCE_EXHAUSTED", 429, "rate_limit_error", "insufficient_quota"),
+            (500, "INTERNAL", 500, "server_error", "internal"),
+            (503, "UNAVAILABLE", 503, "server_error", "service_unavailable"),
+        ];
+        for (upstream, rpc_status, status, error_type, code) in cases {
+            let error = ApiError::from_upstream(upstream, &status_body(upstream, rpc_status));
+            assert_eq!(error.status.as_u16(), status, "{} {}", upstream, rpc_status);
+            assert_eq!(error.error_type, error_type, "{} {}", upstream, rpc_status);
+            assert_eq!(error.code.as_deref(), Some(code), "{} {}", upstream, rpc_status);
+            assert_eq!(error.message, "upstream says no");
+        }
+    }
+
+    #[test]
+    fn reads_the_retry_delay_from_retry_info() {
+        let body = json!({ "error": { "code": 429, "message": "slow down", "status": "RESOURCE_EXHAUSTED", "details": [
+            { "@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "RATE_LIMIT_EXCEEDED" },
+            { "@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "3.5s" },
+        ] } });
+        let error = ApiError::from_upstream(429, &body.to_string());
+        assert_eq!(error.retry_after, Some(Duration::from_millis(3500)));
+        assert_eq!(ApiError::from_upstream(429, &status_body(429, "RESOURCE_EXHAUSTED")).retry_after, None);
+    }
+
+    #[test]
+    fn unwraps_array_wrapped_statuses() {
+        let body = format!("[{}]", status_body(429, "RESOURCE_EXHAUSTED"));
+        let error = ApiError::from_upstream(429, &body);
+        assert_eq!(error.code.as_deref(), Some("insufficient_quota"));
+        assert_eq!(error.message, "upstream says no");
+    }
+
+    #[test]
+    fn keeps_non_json_bodies_as_the_message() {
+        let error = ApiError::from_upstream(502, "<html>Bad Gateway</html>\n");
+        assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.message, "<html>Bad Gateway</html>");
+        assert_eq!(error.code, None);
+        assert_eq!(ApiError::from_upstream(503, "").message, "Upstream returned HTTP 503");
+    }
+
+    #[test]
+    fn parses_protobuf_durations() {
+        assert_eq!(parse_duration("3s"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_duration(" 0.25s "), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("3"), None);
+        assert_eq!(parse_duration("-1s"), None);
+    }
+
+    #[test]
+    fn rounds_retry_after_up_to_whole_seconds() {
+        let retry_after = |delay| {
+            let response = ApiError::rate_limited("slow down", delay).into_response();
+            response.headers().get(RETRY_AFTER).map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(retry_after(Some(Duration::from_millis(1200))).as_deref(), Some("2"));
+        assert_eq!(retry_after(Some(Duration::from_millis(1))).as_deref(), Some("1"));
+        assert_eq!(retry_after(Some(Duration::from_secs(3))).as_deref(), Some("3"));
+        assert_eq!(retry_after(None), None);
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Result, Context, anyhow};
+use base64::Engine;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use crate::config::AuthConfig;
+use crate::oauth::{get_user_email, refresh_access_token};
+
+/// `oauth_creds.json` as written by gemini-cli (google-auth-library
+/// credentials).
+#[derive(Debug, Deserialize)]
+struct GeminiCliCredentials {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    /// Milliseconds since the epoch.
+    expiry_date: Option<u64>,
+    id_token: Option<String>,
+}
+
+/// `~/.gemini/oauth_creds.json`.
+pub fn default_path() -> PathBuf {
+    let mut path = home::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push(".gemini");
+    path.push("oauth_creds.json");
+    path
+}
+
+/// Reads gemini-cli credentials as they are, without contacting Google.
+pub fn load(path: &Path) -> Result<AuthConfig> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read gemini-cli credentials at {:?}", path))?;
+    let creds: GeminiCliCredentials = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse gemini-cli credentials at {:?}", path))?;
+
+    let refresh_token = creds.refresh_token
+        .filter(|t| !t.is_empty())
+        .ok_or_else(|| anyhow!("{:?} has no refresh token; log in with gemini-cli first", path))?;
+    let email = creds.id_token.as_deref()
+        .and_then(email_from_id_token)
+        .or_else(|| active_account(path));
+
+    Ok(AuthConfig {
+        access_token: creds.access_token.unwrap_or_default(),
+        refresh_token,
+        expires_at: creds.expiry_date.unwrap_or(0) / 1000,
+        email,
+        project_id: None,
+        sync_from: None,
+        credentials_file: None,
+    })
+}
+
+/// Imports gemini-cli credentials, checking with a refresh that the refresh
+/// token still works. With `sync`, the server keeps adopting the tokens
+/// gemini-cli writes to the file.
+pub async fn import(http: &reqwest::Client, path: &Path, sync: bool, token_url: &str) -> Result<AuthConfig> {
+    let mut auth = load(path)?;
+    let (access_token, expires_at) = refresh_access_token(http, &auth.refresh_token, token_url).await
+        .context("The imported refresh token was rejected; log in with gemini-cli again")?;
+    auth.access_token = access_token;
+    auth.expires_at = expires_at;
+
+    if auth.email.is_none() {
+        auth.email = get_user_email(http, &auth.access_token).await.ok();
+    }
+    if sync {
+        auth.sync_from = Some(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+    }
+    Ok(auth)
+}
+
+/// The `email` claim of an ID token. The token came from Google over TLS,
+/// so its signature is not checked.
+fn email_from_id_token(id_token: &str) -> Option<String> {
+    let payload = id_token.split('.').nth(1)?;
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&bytes).ok()?;
+    claims["email"].as_str().map(|e| e.to_string())
+}
+
+/// The active account from `google_accounts.json` next to the credentials.
+fn active_account(creds_path: &Path) -> Option<String> {
+    let path = creds_path.with_file_name("google_accounts.json");
+    let accounts: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    accounts["active"].as_str().map(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_oauth_creds_with_email_from_id_token() {
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(r#"{"email":"dev@example.com"}"#);
+        let dir = std::env::temp_dir().join(format!("gemini-cli-test-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("oauth_creds.json");
+        std::fs::write(&path, format!(
+            r#"{{"access_token":"ya29.a","refresh_token":"1//r","scope":"email","token_type":"Bearer","id_token":"h.{}.s","expiry_date":1760000000123}}"#,
+            claims,
+        )).unwrap();
+
+        let auth = load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(auth.access_token, "ya29.a");
+        assert_eq!(auth.refresh_token, "1//r");
+        assert_eq!(auth.expires_at, 1760000000);
+        assert_eq!(auth.email.as_deref(), Some("dev@example.com"));
+        assert!(auth.sync_from.is_none());
+    }
+}
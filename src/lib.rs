@@ -1,6 +1,11 @@
+pub mod api_keys;
+pub mod auth;
 pub mod config;
 pub mod constants;
+pub mod error;
+pub mod gemini_cli;
 pub mod oauth;
 pub mod proxy;
 pub mod server;
+pub mod sse;
 pub mod transform;
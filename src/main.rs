@@ -1,8 +1,12 @@
 use clap::{Parser, Subcommand};
-use gemini_proxy::config::{load_config, save_config};
-use gemini_proxy::oauth::{start_oauth_flow, refresh_access_token};
+use gemini_proxy::api_keys;
+use gemini_proxy::gemini_cli;
+use gemini_proxy::config::{load_config, save_config, AuthConfig};
+use gemini_proxy::oauth::{application_default_credentials, get_user_email, start_oauth_flow, CredentialProvider, LoginOptions};
+use gemini_proxy::proxy::UpstreamClient;
 use gemini_proxy::server::start_server;
 use anyhow::Result;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "gemini-proxy")]
@@ -14,11 +18,33 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Authenticate with Google
-    Login,
+    /// Authenticate with Google, adding the account to the pool
+    Login {
+        /// Paste the redirect URL or code instead of using a local callback
+        #[arg(long)]
+        no_browser: bool,
+        /// Port for the local OAuth callback (default 8085, or a free port if busy)
+        #[arg(long)]
+        callback_port: Option<u16>,
+        /// Host for the local OAuth callback
+        #[arg(long, default_value = "localhost")]
+        callback_host: String,
+        /// Import the login of gemini-cli (default ~/.gemini/oauth_creds.json)
+        #[arg(long, value_name = "PATH")]
+        import_gemini_cli: Option<Option<PathBuf>>,
+        /// Keep adopting tokens from the imported file while the server runs
+        #[arg(long, requires = "import_gemini_cli")]
+        sync: bool,
+        /// Use a service account key or authorized_user credentials file
+        #[arg(long, value_name = "PATH", conflicts_with = "import_gemini_cli")]
+        credentials: Option<PathBuf>,
+    },
     /// Remove saved credentials
-    Logout,
-    /// Check authentication status
+    Logout {
+        /// Only remove this account (email); all accounts otherwise
+        account: Option<String>,
+    },
+    /// Check authentication status of every account
     Status,
     /// Start the proxy server
     Start {
@@ -33,6 +59,29 @@ enum Commands {
     SetProject {
         #[arg(name = "projectId")]
         project_id: String,
+        /// Only set it for this account (email); all accounts otherwise
+        #[arg(long)]
+        account: Option<String>,
+    },
+    /// Manage the API keys clients must present
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Create a new key and print it once
+    Create {
+        /// Name to recognize the key by
+        label: String,
+    },
+    /// List the configured keys
+    List,
+    /// Revoke a key by id or label
+    Revoke {
+        key: String,
     },
 }
 
@@ -42,92 +91,191 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Login => {
-            println!("\n🔐 Starting OAuth flow for Gemini...\n");
-            let result = start_oauth_flow().await?;
-            
+        Commands::Login { no_browser, callback_port, callback_host, import_gemini_cli, sync, credentials } => {
             let mut config = load_config()?;
-            config.auth = Some(gemini_proxy::config::AuthConfig {
-                access_token: result.access_token,
-                refresh_token: result.refresh_token,
-                expires_at: result.expires_at,
-                email: Some(result.email.clone()),
+            let token_url = config.token_url().to_string();
+            let http = UpstreamClient::from_config(&config.upstream)?.client;
+            let auth = match (import_gemini_cli, credentials) {
+                (Some(path), _) => {
+                    let path = path.unwrap_or_else(gemini_cli::default_path);
+                    println!("\n📥 Importing gemini-cli credentials from {}...", path.display());
+                    gemini_cli::import(&http, &path, sync, &token_url).await?
+                }
+                (None, Some(path)) => {
+                    let path = path.canonicalize().unwrap_or(path);
+                    println!("\n🔑 Checking credentials in {}...", path.display());
+                    let provider = CredentialProvider::from_file(&path, config.token_url.as_deref())?;
+                    let (access_token, expires_at) = provider.fetch_token(&http).await?;
+                    let email = match provider.email() {
+                        Some(email) => Some(email.to_string()),
+                        None => get_user_email(&http, &access_token).await.ok(),
+                    };
+                    AuthConfig {
+                        access_token,
+                        expires_at,
+                        email,
+                        credentials_file: Some(path),
+                        ..Default::default()
+                    }
+                }
+                (None, None) => {
+                    println!("\n🔐 Starting OAuth flow for Gemini...\n");
+                    let options = LoginOptions { no_browser, callback_host, callback_port, token_url };
+                    let result = start_oauth_flow(&http, &options).await?;
+                    AuthConfig {
+                        access_token: result.access_token,
+                        refresh_token: result.refresh_token,
+                        expires_at: result.expires_at,
+                        email: result.email,
+                        ..Default::default()
+                    }
+                }
+            };
+
+            let (email, expires_at) = (auth.label().to_string(), auth.expires_at);
+            // Logging in again with the same account replaces its tokens.
+            let existing = config.accounts.iter_mut().find(|a| {
+                (a.email.is_some() && a.email == auth.email)
+                    || (a.refresh_token == auth.refresh_token && a.credentials_file == auth.credentials_file)
             });
+            match existing {
+                Some(existing) => {
+                    existing.access_token = auth.access_token;
+                    existing.refresh_token = auth.refresh_token;
+                    existing.expires_at = auth.expires_at;
+                    existing.sync_from = auth.sync_from;
+                    existing.credentials_file = auth.credentials_file;
+                }
+                None => config.accounts.push(auth),
+            }
             save_config(&config)?;
 
             println!("\n═══════════════════════════════════════════════════════");
             println!("✅ Authentication successful!");
             println!("═══════════════════════════════════════════════════════");
-            println!("   Email: {}", result.email);
-            println!("   Expires: {}", chrono::DateTime::from_timestamp(result.expires_at as i64, 0)
-                .map(|dt| dt.to_rfc3339())
-                .unwrap_or_else(|| "Unknown".to_string()));
+            println!("   Email: {}", email);
+            println!("   Expires: {}", format_timestamp(expires_at));
+            println!("   Accounts: {}", config.accounts.len());
             println!("\nYou can now start the proxy server:");
             println!("   gemini-proxy start");
             println!("═══════════════════════════════════════════════════════\n");
         }
-        Commands::Logout => {
+        Commands::Logout { account } => {
             let mut config = load_config()?;
-            if config.auth.is_some() {
-                config.auth = None;
+            let before = config.accounts.len();
+            match &account {
+                Some(email) => config.accounts.retain(|a| a.email.as_deref() != Some(email.as_str())),
+                None => config.accounts.clear(),
+            }
+            let removed = before - config.accounts.len();
+            if removed > 0 {
                 save_config(&config)?;
-                println!("✅ Logged out successfully");
+                println!("✅ Logged out {} account(s)", removed);
+            } else if let Some(email) = account {
+                println!("No account {} found", email);
             } else {
                 println!("No authentication found");
             }
         }
         Commands::Status => {
             let config = load_config()?;
-            let auth = config.auth.as_ref();
-            
-            match auth {
-                Some(auth) => {
-                    let now = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)?
-                        .as_secs();
-                    let is_valid = now < auth.expires_at;
-                    
-                    println!("\n═══════════════════════════════════════════════════════");
-                    println!("📋 Authentication Status");
-                    println!("═══════════════════════════════════════════════════════");
-                    println!("   Email: {}", auth.email.as_deref().unwrap_or("Unknown"));
-                    println!("   Project ID: {}", config.project_id.as_deref().unwrap_or("auto-detected"));
-                    println!("   Expires: {}", chrono::DateTime::from_timestamp(auth.expires_at as i64, 0)
-                        .map(|dt| dt.to_rfc3339())
-                        .unwrap_or_else(|| "Unknown".to_string()));
-                    println!("   Valid: {}", if is_valid { "✅ Yes" } else { "❌ No (expired)" });
-                    println!("═══════════════════════════════════════════════════════\n");
+            let adc = application_default_credentials();
+            if config.accounts.is_empty() && adc.is_none() {
+                println!("❌ Not authenticated. Run 'gemini-proxy login' first.");
+                return Ok(());
+            }
+
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            println!("\n═══════════════════════════════════════════════════════");
+            println!("📋 Authentication Status ({} account(s))", config.accounts.len());
+            println!("═══════════════════════════════════════════════════════");
+            for auth in &config.accounts {
+                let is_valid = now < auth.expires_at;
+                println!("   Email: {}", auth.email.as_deref().unwrap_or("Unknown"));
+                println!("   Project ID: {}", auth.project_id.as_deref().unwrap_or("auto-detected"));
+                if let Some(path) = &auth.sync_from {
+                    println!("   Synced from: {}", path.display());
                 }
-                None => {
-                    println!("❌ Not authenticated. Run 'gemini-proxy login' first.");
+                if let Some(path) = &auth.credentials_file {
+                    println!("   Credentials file: {}", path.display());
                 }
+                println!("   Expires: {}", format_timestamp(auth.expires_at));
+                println!("   Valid: {}", if is_valid { "✅ Yes" } else { "❌ No (expired, refreshed on next use)" });
+                println!();
+            }
+            if let Some(path) = adc.filter(|path| !config.accounts.iter().any(|a| a.credentials_file.as_ref() == Some(path))) {
+                println!("   Application default credentials: {}", path.display());
+                println!();
             }
+            println!("   Rotation: {:?}, {}s cooldown after a 429", config.rotation.strategy, config.rotation.cooldown_secs);
+            println!("   Live cooldowns of a running server: GET /health/accounts");
+            println!("═══════════════════════════════════════════════════════\n");
         }
         Commands::Start { port, host } => {
-            let mut config = load_config()?;
-            let auth = config.auth.as_mut().ok_or_else(|| anyhow::anyhow!("Not authenticated. Run 'gemini-proxy login' first."))?;
-            
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_secs();
-            
-            if now >= auth.expires_at - 60 {
-                println!("🔄 Access token expired, refreshing...");
-                let (new_token, new_expires) = refresh_access_token(&auth.refresh_token).await?;
-                auth.access_token = new_token;
-                auth.expires_at = new_expires;
-                save_config(&config)?;
+            let config = load_config()?;
+            if config.accounts.is_empty() && application_default_credentials().is_none() {
+                anyhow::bail!("Not authenticated. Run 'gemini-proxy login' or set GOOGLE_APPLICATION_CREDENTIALS.");
             }
             
+            // The server refreshes the access tokens itself while it runs.
             start_server(&host, port).await?;
         }
-        Commands::SetProject { project_id } => {
+        Commands::SetProject { project_id, account } => {
             let mut config = load_config()?;
-            config.project_id = Some(project_id.clone());
+            let mut updated = 0;
+            for auth in config.accounts.iter_mut() {
+                if account.is_none() || auth.email == account {
+                    auth.project_id = Some(project_id.clone());
+                    updated += 1;
+                }
+            }
+            if updated == 0 {
+                anyhow::bail!("No matching account. Run 'gemini-proxy login' first.");
+            }
             save_config(&config)?;
-            println!("✅ Project ID set to: {}", project_id);
+            println!("✅ Project ID set to: {} ({} account(s))", project_id, updated);
         }
+        Commands::Keys { command } => match command {
+            KeysCommand::Create { label } => {
+                let mut config = load_config()?;
+                let (key, entry) = api_keys::generate(&label);
+                println!("✅ Created API key '{}' ({})", entry.label, entry.id);
+                println!("\n   {}\n", key);
+                println!("Store it now, it cannot be shown again. Restart a running server to apply.");
+                config.api_keys.push(entry);
+                save_config(&config)?;
+            }
+            KeysCommand::List => {
+                let config = load_config()?;
+                if config.api_keys.is_empty() {
+                    println!("No API keys configured; the proxy accepts any request.");
+                }
+                for key in &config.api_keys {
+                    println!("{}  {}…  {}  {}", key.id, key.prefix, format_timestamp(key.created_at), key.label);
+                }
+            }
+            KeysCommand::Revoke { key } => {
+                let mut config = load_config()?;
+                let before = config.api_keys.len();
+                config.api_keys.retain(|k| k.id != key && k.label != key);
+                match before - config.api_keys.len() {
+                    0 => anyhow::bail!("No API key with id or label '{}'", key),
+                    n => {
+                        save_config(&config)?;
+                        println!("✅ Revoked {} key(s). Restart a running server to apply.", n);
+                    }
+                }
+            }
+        },
     }
 
     Ok(())
 }
+
+fn format_timestamp(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|dt| dt.to_rfc3339())
+        .unwrap_or_else(|| "Unknown".to_string())
+}
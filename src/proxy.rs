@@ -1,12 +1,141 @@
-use anyhow::{Result, anyhow};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, ACCEPT};
-use crate::config::{load_config, save_config};
-use crate::constants::{GEMINI_CODE_ASSIST_ENDPOINT, CODE_ASSIST_HEADERS};
-use crate::transform::{OpenAIRequest, transform_openai_to_gemini};
+use anyhow::{Result, Context, anyhow};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, ACCEPT, RETRY_AFTER};
+use crate::auth::Account;
+use crate::config::{RetryConfig, UpstreamConfig};
+use crate::server::ServerState;
+use crate::constants::CODE_ASSIST_HEADERS;
+use crate::error::{ApiError, UPSTREAM_AUTHENTICATION_ERROR};
+use crate::transform::{
+    OpenAIContent, OpenAIContentPart, OpenAIRequest, TransformOptions,
+    guess_mime_type, transform_openai_to_gemini,
+};
+use base64::Engine;
 use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
-pub async fn load_managed_project(access_token: &str) -> Result<Option<String>> {
-    let client = reqwest::Client::new();
+/// Largest remote image we are willing to inline into a request.
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// The HTTP client shared by all upstream calls, so connections are pooled
+/// and the timeout, proxy and CA settings apply everywhere.
+pub struct UpstreamClient {
+    pub client: reqwest::Client,
+    /// Downloads client-supplied image URLs; only reaches public addresses
+    /// and never goes through the configured proxy.
+    pub images: reqwest::Client,
+    pub base_url: String,
+    /// Total time allowed for a call whose response is not streamed. The
+    /// clients only bound connecting and each read, so streams can run on.
+    pub request_timeout: Duration,
+}
+
+impl UpstreamClient {
+    pub fn from_config(config: &UpstreamConfig) -> Result<Self> {
+        Ok(UpstreamClient {
+            client: client_builder(config)?.build()?,
+            // A proxy would resolve names itself and bypass the check, so
+            // images are always downloaded directly.
+            images: client_builder(config)?
+                .timeout(Duration::from_secs(config.request_timeout_secs))
+                .no_proxy()
+                .dns_resolver(PublicResolver)
+                .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                    if attempt.previous().len() >= 10 {
+                        attempt.error("too many redirects")
+                    } else if let Err(e) = check_public_url(attempt.url()) {
+                        attempt.error(e.to_string())
+                    } else {
+                        attempt.follow()
+                    }
+                }))
+                .build()?,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            request_timeout: Duration::from_secs(config.request_timeout_secs),
+        })
+    }
+}
+
+fn client_builder(config: &UpstreamConfig) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(config.read_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60));
+
+    if let Some(proxy_url) = &config.proxy {
+        let proxy = reqwest::Proxy::all(proxy_url)
+            .with_context(|| format!("Invalid upstream proxy URL {}", proxy_url))?
+            .no_proxy(config.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string));
+        builder = builder.proxy(proxy);
+    }
+
+    let mut certificates = Vec::new();
+    for path in &config.ca_certificates {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Failed to read CA certificate at {:?}", path))?;
+        certificates.extend(reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Failed to parse CA certificate at {:?}", path))?);
+    }
+    if !certificates.is_empty() {
+        builder = builder.tls_certs_merge(certificates);
+    }
+    Ok(builder)
+}
+
+/// Resolves names like the system resolver, but drops loopback, private
+/// and link-local addresses so client-supplied URLs cannot reach the
+/// proxy's own network.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Rejects URLs whose host is a non-public IP literal, which bypasses the
+/// resolver.
+fn check_public_url(url: &url::Url) -> Result<()> {
+    let ip = match url.host() {
+        Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return Ok(()),
+    };
+    if !is_public(ip) {
+        return Err(anyhow!("{} is not a public address", ip));
+    }
+    Ok(())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+                || a == 0 || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                    || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+pub async fn load_managed_project(upstream: &UpstreamClient, access_token: &str) -> Result<Option<String>> {
     let mut headers = HeaderMap::new();
     headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", access_token))?);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
@@ -23,7 +152,8 @@ pub async fn load_managed_project(access_token: &str) -> Result<Option<String>>
         },
     });
 
-    let res = client.post(format!("{}/v1internal:loadCodeAssist", GEMINI_CODE_ASSIST_ENDPOINT))
+    let res = upstream.client.post(format!("{}/v1internal:loadCodeAssist", upstream.base_url))
+        .timeout(upstream.request_timeout)
         .headers(headers)
         .json(&body)
         .send()
@@ -42,46 +172,586 @@ pub async fn load_managed_project(access_token: &str) -> Result<Option<String>>
     Ok(project_id)
 }
 
-pub async fn get_auth() -> Result<(String, String)> {
-    let mut config = load_config()?;
-    let auth = config.auth.as_ref().ok_or_else(|| anyhow!("No authentication found. Run 'gemini-proxy login' first."))?;
-    
-    let access_token = auth.access_token.clone();
-    let mut project_id = config.project_id.clone().unwrap_or_else(|| "default".to_string());
+pub async fn get_auth(account: &Account, upstream: &UpstreamClient) -> Result<(String, String)> {
+    let access_token = account.access_token().await?;
+
+    let project_id = match account.project_id().await {
+        Some(project_id) => project_id,
+        None => match load_managed_project(upstream, &access_token).await {
+            Ok(Some(managed)) => {
+                account.set_project_id(&managed).await;
+                managed
+            }
+            _ => "default".to_string(),
+        },
+    };
 
-    if project_id == "default" {
-        if let Ok(Some(managed)) = load_managed_project(&access_token).await {
-            project_id = managed;
-            config.project_id = Some(project_id.clone());
-            save_config(&config)?;
+    Ok((access_token, project_id))
+}
+
+async fn fetch_image_as_data_url(client: &reqwest::Client, url: &str) -> Result<String> {
+    check_public_url(&url::Url::parse(url)?)?;
+    let mut res = client.get(url).send().await?.error_for_status()?;
+    if res.content_length().is_some_and(|len| len > MAX_IMAGE_BYTES as u64) {
+        return Err(anyhow!("image is larger than {} bytes", MAX_IMAGE_BYTES));
+    }
+    let mime_type = res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or(v).trim().to_string())
+        .filter(|v| !v.is_empty() && v != "application/octet-stream")
+        .unwrap_or_else(|| guess_mime_type(url).to_string());
+
+    // Content-Length may be missing or wrong, so count while reading.
+    let mut bytes = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if bytes.len() + chunk.len() > MAX_IMAGE_BYTES {
+            return Err(anyhow!("image is larger than {} bytes", MAX_IMAGE_BYTES));
         }
+        bytes.extend_from_slice(&chunk);
     }
 
-    Ok((access_token, project_id))
+    let data = base64::engine::general_purpose::STANDARD.encode(&bytes);
+    Ok(format!("data:{};base64,{}", mime_type, data))
+}
+
+/// Gemini cannot fetch arbitrary http(s) URLs, so download remote images and
+/// replace them with data URLs before the request is transformed.
+async fn inline_remote_images(client: &reqwest::Client, body: &mut OpenAIRequest) -> Result<()> {
+    for (i, msg) in body.messages.iter_mut().enumerate() {
+        let Some(OpenAIContent::Parts(parts)) = &mut msg.content else {
+            continue;
+        };
+        for (j, part) in parts.iter_mut().enumerate() {
+            let OpenAIContentPart::ImageUrl { image_url } = part else {
+                continue;
+            };
+            if !(image_url.url.starts_with("http://") || image_url.url.starts_with("https://")) {
+                continue;
+            }
+            image_url.url = fetch_image_as_data_url(client, &image_url.url).await.map_err(|e| {
+                ApiError::invalid_request(
+                    format!("Failed to download image {}: {:#}", image_url.url, e),
+                    Some(&format!("messages[{}].content[{}].image_url", i, j)),
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// A successful upstream response together with the model that produced it.
+pub struct UpstreamResponse {
+    pub response: reqwest::Response,
+    /// Differs from the requested model when a fallback answered.
+    pub model: String,
+}
+
+/// Quota and server errors that survived the retries move on to the next
+/// model in the fallback chain.
+fn should_fall_back(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<ApiError>().is_some_and(|e| {
+        let code = e.code.as_deref();
+        e.status.as_u16() == 429 || code == Some("resource_exhausted")
+            || (e.status.is_server_error() && code != Some(UPSTREAM_AUTHENTICATION_ERROR))
+    })
 }
 
-pub async fn proxy_request(body: OpenAIRequest) -> Result<reqwest::Response> {
-    let (token, project_id) = get_auth().await?;
-    let (url, gemini_body, streaming) = transform_openai_to_gemini(&body, &project_id);
+pub async fn proxy_request(state: &ServerState, mut body: OpenAIRequest) -> Result<UpstreamResponse> {
+    inline_remote_images(&state.upstream.images, &mut body).await?;
 
-    let client = reqwest::Client::new();
     let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token))?);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
     
     for (key, value) in CODE_ASSIST_HEADERS {
         headers.insert(reqwest::header::HeaderName::from_bytes(key.as_bytes())?, HeaderValue::from_str(value)?);
     }
 
-    if streaming {
+    if body.stream.unwrap_or(false) {
         headers.insert(ACCEPT, HeaderValue::from_static("text/event-stream"));
     }
 
-    let res = client.post(url)
-        .headers(headers)
-        .json(&gemini_body)
-        .send()
-        .await?;
+    let mut models = vec![body.model.clone()];
+    models.extend(state.config.model_fallbacks.get(&body.model).cloned().unwrap_or_default());
+
+    for (i, model) in models.iter().enumerate() {
+        body.model = model.clone();
+        match send_with_accounts(state, &body, &headers).await {
+            Ok(response) => return Ok(UpstreamResponse { response, model: model.clone() }),
+            Err(e) if i + 1 < models.len() && should_fall_back(&e) => {
+                log::warn!("Model {} failed ({}); falling back to {}", model, e, models[i + 1]);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!("the model list always contains the requested model")
+}
+
+/// Sends `body` with the next account of the pool. A rate-limited account
+/// is put on cooldown and the request moves on to another account at once;
+/// only the last available account retries 429s with backoff. Accounts
+/// whose token cannot be refreshed are skipped the same way.
+async fn send_with_accounts(state: &ServerState, body: &OpenAIRequest, headers: &HeaderMap) -> Result<reqwest::Response> {
+    let client = &state.upstream.client;
+    let options = TransformOptions::from_config(&state.config);
+    let mut last_error = None;
+
+    // Each account gets one turn; the last one retries with backoff.
+    for attempt in 1..=state.auth.accounts().len() {
+        let account = match state.auth.next_account(&body.model) {
+            Ok(account) => account,
+            Err(e) => return Err(last_error.unwrap_or(e)),
+        };
+        let (mut token, project_id) = match get_auth(&account, &state.upstream).await {
+            Ok(auth) => auth,
+            Err(e) => {
+                state.auth.mark_unusable(&account, &e);
+                last_error = Some(e);
+                continue;
+            }
+        };
+        // The translation depends on the model (e.g. thinking config) and
+        // the project, so redo it for every model and account.
+        let (url, gemini_body, streaming) = transform_openai_to_gemini(body, &project_id, &options)?;
+        let last_account = state.auth.available(&body.model) <= 1 || attempt == state.auth.accounts().len();
+        let mut refreshed = false;
+        log::debug!("Sending {} request with {}", body.model, account.label());
+
+        let result = loop {
+            let result = send_with_retry(&state.config.retry, last_account, || {
+                let request = client.post(&url)
+                    .headers(headers.clone())
+                    .bearer_auth(&token)
+                    .json(&gemini_body);
+                // A stream may legitimately run longer than any total limit.
+                if streaming { request } else { request.timeout(state.upstream.request_timeout) }
+            }).await;
+
+            // A token revoked or expired early gets one forced refresh.
+            match result {
+                Err(e) if !refreshed && is_unauthorized(&e) => {
+                    log::warn!("Upstream rejected the access token of {}; refreshing and retrying", account.label());
+                    match account.force_refresh(&token).await {
+                        Ok(new_token) => token = new_token,
+                        Err(refresh_error) => {
+                            state.auth.mark_unusable(&account, &refresh_error);
+                            break Err(e);
+                        }
+                    }
+                    refreshed = true;
+                }
+                other => break other,
+            }
+        };
+
+        match result {
+            Err(e) => match e.downcast_ref::<ApiError>() {
+                Some(error) if is_rate_limited(error) => {
+                    state.auth.mark_rate_limited(&account, &body.model, error.retry_after);
+                    if last_account {
+                        return Err(e);
+                    }
+                    last_error = Some(e);
+                }
+                // Already taken out of rotation when the refresh failed.
+                _ if is_unauthorized(&e) && !refreshed && !last_account => last_error = Some(e),
+                _ => return Err(e),
+            },
+            ok => return ok,
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow!("No authentication found. Run 'gemini-proxy login' first.")))
+}
 
-    Ok(res)
+fn is_unauthorized(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<ApiError>().is_some_and(|e| e.code.as_deref() == Some(UPSTREAM_AUTHENTICATION_ERROR))
+}
+
+fn is_rate_limited(error: &ApiError) -> bool {
+    error.status.as_u16() == 429 || error.code.as_deref() == Some("resource_exhausted")
+}
+
+/// Rate limits are only retried on the same account when there is no
+/// other account to move on to.
+fn is_retryable(error: &ApiError, retry_rate_limits: bool) -> bool {
+    error.status.as_u16() == 503 || (retry_rate_limits && is_rate_limited(error))
+}
+
+/// Exponential backoff with jitter: a random delay between half and all of
+/// `initial * 2^(attempt - 1)`, capped at the configured maximum.
+fn backoff_delay(retry: &RetryConfig, attempt: u32) -> Duration {
+    let exp = retry.initial_backoff_ms.saturating_mul(1u64 << (attempt - 1).min(16));
+    let max = exp.min(retry.max_backoff_ms).max(1);
+    Duration::from_millis(rand::random_range(max / 2..=max))
+}
+
+/// Sends the request built by `build`, retrying availability errors and,
+/// if `retry_rate_limits` is set, quota errors. Only the status line is
+/// awaited before deciding, so a streaming response is never retried once
+/// its body has started flowing.
+async fn send_with_retry<F>(retry: &RetryConfig, retry_rate_limits: bool, build: F) -> Result<reqwest::Response>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    let started = Instant::now();
+    let deadline = Duration::from_secs(retry.deadline_secs);
+    let mut attempt = 1;
+
+    loop {
+        let res = build().send().await?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+
+        let retry_after_header = res.headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = res.text().await.unwrap_or_default();
+        let mut error = ApiError::from_upstream(status.as_u16(), &body);
+        if error.retry_after.is_none() {
+            error.retry_after = retry_after_header;
+        }
+
+        if !is_retryable(&error, retry_rate_limits) || attempt >= retry.max_attempts {
+            log::warn!("Upstream returned {}: {}", status, body);
+            return Err(error.into());
+        }
+
+        let delay = error.retry_after.unwrap_or_else(|| backoff_delay(retry, attempt));
+        if started.elapsed() + delay > deadline {
+            log::warn!("Upstream returned {}; retry in {:?} would exceed the {:?} deadline", status, delay, deadline);
+            return Err(error.into());
+        }
+
+        log::warn!(
+            "Upstream returned {} ({}); retrying in {:?} (attempt {}/{})",
+            status, error.message, delay, attempt + 1, retry.max_attempts
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::auth::AuthManager;
+    use crate::config::{AuthConfig, Config};
+    use axum::response::IntoResponse;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    /// A local stand-in for Code Assist. `respond` maps the model and bearer
+    /// token of each request to a status and body; requests are recorded.
+    pub(crate) struct StandIn {
+        base_url: String,
+        requests: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl StandIn {
+        pub(crate) async fn start(respond: impl Fn(&str, &str) -> (u16, Value) + Send + Sync + 'static) -> Self {
+            Self::start_with_headers(move |model, token| {
+                let (status, body) = respond(model, token);
+                (status, HeaderMap::new(), body)
+            }).await
+        }
+
+        /// Like `start`, with response headers.
+        pub(crate) async fn start_with_headers(respond: impl Fn(&str, &str) -> (u16, HeaderMap, Value) + Send + Sync + 'static) -> Self {
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&requests);
+            let respond = Arc::new(respond);
+            let app = axum::Router::new().fallback(move |headers: HeaderMap, axum::Json(body): axum::Json<Value>| {
+                let (recorded, respond) = (Arc::clone(&recorded), Arc::clone(&respond));
+                async move {
+                    let model = body["model"].as_str().unwrap_or_default().to_string();
+                    let token = headers.get(AUTHORIZATION)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.strip_prefix("Bearer "))
+                        .unwrap_or_default()
+                        .to_string();
+                    recorded.lock().unwrap().push((model.clone(), token.clone()));
+                    let (status, headers, body) = respond(&model, &token);
+                    (axum::http::StatusCode::from_u16(status).unwrap(), headers, axum::Json(body))
+                }
+            });
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            StandIn { base_url, requests }
+        }
+
+        pub(crate) fn requests(&self) -> Vec<(String, String)> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        pub(crate) fn state(&self, accounts: Vec<AuthConfig>, configure: impl FnOnce(&mut Config)) -> ServerState {
+            let mut config = Config { accounts, ..Default::default() };
+            config.upstream.base_url = self.base_url.clone();
+            config.retry = RetryConfig { max_attempts: 2, initial_backoff_ms: 10, max_backoff_ms: 20, deadline_secs: 10 };
+            configure(&mut config);
+            let upstream = UpstreamClient::from_config(&config.upstream).unwrap();
+            let auth = Arc::new(AuthManager::from_config(&config, upstream.client.clone()));
+            ServerState { port: 0, host: "127.0.0.1".to_string(), config, auth, upstream }
+        }
+    }
+
+    /// An account whose token is valid for a long time.
+    pub(crate) fn account(token: &str) -> AuthConfig {
+        AuthConfig {
+            access_token: token.to_string(),
+            expires_at: u64::MAX / 2,
+            email: Some(token.to_string()),
+            project_id: Some("p".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// An account whose token is expired and cannot be refreshed.
+    fn broken_account() -> AuthConfig {
+        AuthConfig {
+            email: Some("broken".to_string()),
+            project_id: Some("p".to_string()),
+            credentials_file: Some("/nonexistent/credentials.json".into()),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn request(model: &str) -> OpenAIRequest {
+        serde_json::from_value(json!({ "model": model, "messages": [{ "role": "user", "content": "hi" }] })).unwrap()
+    }
+
+    pub(crate) fn ok_body() -> Value {
+        json!({ "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "ok" }] }, "finishReason": "STOP" }] } })
+    }
+
+    #[tokio::test]
+    async fn skips_an_account_whose_token_cannot_be_refreshed() {
+        let upstream = StandIn::start(|_, _| (200, ok_body())).await;
+        let state = upstream.state(vec![account("a"), broken_account(), account("c")], |_| {});
+
+        for _ in 0..6 {
+            proxy_request(&state, request("m")).await.unwrap();
+        }
+        let tokens: Vec<_> = upstream.requests().into_iter().map(|(_, token)| token).collect();
+        assert_eq!(tokens.len(), 6);
+        assert!(tokens.iter().all(|t| t == "a" || t == "c"), "{:?}", tokens);
+        assert_eq!(state.auth.available("m"), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_the_refresh_error_when_every_account_is_broken() {
+        let upstream = StandIn::start(|_, _| (200, ok_body())).await;
+        let state = upstream.state(vec![broken_account(), broken_account()], |_| {});
+
+        let Err(error) = proxy_request(&state, request("m")).await else { panic!("request succeeded") };
+        assert!(error.to_string().contains("credentials.json"), "{}", error);
+        assert!(upstream.requests().is_empty());
+    }
+
+    fn retry(max_attempts: u32, deadline_secs: u64) -> RetryConfig {
+        RetryConfig { max_attempts, initial_backoff_ms: 10, max_backoff_ms: 20, deadline_secs }
+    }
+
+    fn unavailable(retry_delay: Option<&str>) -> Value {
+        let details: Vec<Value> = retry_delay.into_iter()
+            .map(|delay| json!({ "@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": delay }))
+            .collect();
+        json!({ "error": { "code": 503, "message": "overloaded", "status": "UNAVAILABLE", "details": details } })
+    }
+
+    fn retry_after(secs: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, secs.into());
+        headers
+    }
+
+    async fn send(upstream: &StandIn, retry: &RetryConfig, retry_rate_limits: bool) -> Result<reqwest::Response> {
+        let client = reqwest::Client::new();
+        send_with_retry(retry, retry_rate_limits, || client.post(&upstream.base_url).json(&json!({ "model": "m" }))).await
+    }
+
+    fn api_error(result: Result<reqwest::Response>) -> ApiError {
+        let Err(error) = result else { panic!("request succeeded") };
+        error.downcast::<ApiError>().unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_between_half_and_all_of_the_cap() {
+        let retry = RetryConfig { max_attempts: 10, initial_backoff_ms: 100, max_backoff_ms: 1000, deadline_secs: 60 };
+        for (attempt, low, high) in [(1, 50, 100), (2, 100, 200), (3, 200, 400), (4, 400, 800), (5, 500, 1000), (64, 500, 1000)] {
+            for _ in 0..50 {
+                let delay = backoff_delay(&retry, attempt).as_millis();
+                assert!((low..=high).contains(&delay), "attempt {}: {}ms", attempt, delay);
+            }
+        }
+        let delays: std::collections::HashSet<_> = (0..50).map(|_| backoff_delay(&retry, 5)).collect();
+        assert!(delays.len() > 1, "no jitter");
+    }
+
+    #[test]
+    fn only_retries_rate_limits_when_asked() {
+        let error = |status, body: &str| ApiError::from_upstream(status, body);
+        let exhausted = r#"{"error":{"code":429,"message":"quota","status":"RESOURCE_EXHAUSTED"}}"#;
+        assert!(is_retryable(&error(503, ""), false));
+        assert!(!is_retryable(&error(429, ""), false));
+        assert!(is_retryable(&error(429, ""), true));
+        assert!(is_retryable(&error(429, exhausted), true));
+        assert!(!is_retryable(&error(500, ""), true));
+        assert!(!is_retryable(&error(400, ""), true));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let upstream = StandIn::start(|_, _| (503, unavailable(None))).await;
+        let error = api_error(send(&upstream, &retry(3, 10), true).await);
+        assert_eq!(error.status.as_u16(), 503);
+        assert_eq!(upstream.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_rate_limits_unless_asked() {
+        let upstream = StandIn::start(|_, _| (429, json!({}))).await;
+        api_error(send(&upstream, &retry(3, 10), false).await);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn succeeds_once_upstream_recovers() {
+        let upstream = StandIn::start({
+            let calls = std::sync::atomic::AtomicU32::new(0);
+            move |_, _| match calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => (503, unavailable(Some("0.01s"))),
+                _ => (200, ok_body()),
+            }
+        }).await;
+        assert!(send(&upstream, &retry(3, 10), false).await.is_ok());
+        assert_eq!(upstream.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_retry_would_pass_the_deadline() {
+        let upstream = StandIn::start(|_, _| (503, unavailable(Some("30s")))).await;
+        let started = Instant::now();
+        let error = api_error(send(&upstream, &retry(3, 10), false).await);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(error.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_info_takes_precedence_over_retry_after() {
+        let upstream = StandIn::start_with_headers(|_, _| (503, retry_after(30), unavailable(Some("0.01s")))).await;
+        api_error(send(&upstream, &retry(2, 10), false).await);
+        assert_eq!(upstream.requests().len(), 2);
+
+        // Without RetryInfo the header decides, and 30s is past the deadline.
+        let upstream = StandIn::start_with_headers(|_, _| (503, retry_after(30), unavailable(None))).await;
+        let error = api_error(send(&upstream, &retry(2, 10), false).await);
+        assert_eq!(error.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    /// Answers `primary` with `status` and every other model with success.
+    async fn failing(primary: &'static str, status: u16) -> StandIn {
+        StandIn::start(move |model, _| match model {
+            m if m == primary => (status, json!({ "error": { "code": status, "message": "failed" } })),
+            _ => (200, ok_body()),
+        }).await
+    }
+
+    fn with_fallback(config: &mut Config) {
+        config.model_fallbacks.insert("primary".to_string(), vec!["secondary".to_string()]);
+    }
+
+    fn models(upstream: &StandIn) -> Vec<String> {
+        let mut models: Vec<_> = upstream.requests().into_iter().map(|(model, _)| model).collect();
+        models.dedup();
+        models
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_quota_and_server_errors() {
+        for status in [429, 500, 503] {
+            let upstream = failing("primary", status).await;
+            let state = upstream.state(vec![account("a")], with_fallback);
+
+            let res = proxy_request(&state, request("primary")).await.unwrap();
+            assert_eq!(res.model, "secondary", "status {}", status);
+            assert_eq!(models(&upstream), ["primary", "secondary"], "status {}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_fall_back_on_client_or_credential_errors() {
+        for (status, code) in [(400, None), (401, Some(UPSTREAM_AUTHENTICATION_ERROR))] {
+            let upstream = failing("primary", status).await;
+            let state = upstream.state(vec![account("a")], with_fallback);
+
+            let Err(error) = proxy_request(&state, request("primary")).await else { panic!("request succeeded") };
+            assert_eq!(error.downcast_ref::<ApiError>().unwrap().code.as_deref(), code);
+            assert_eq!(models(&upstream), ["primary"], "status {}", status);
+        }
+    }
+
+    /// Serves completions slowly: streams send an event every 300ms for
+    /// 1.5s, other calls answer after 1.5s.
+    async fn slow_upstream() -> String {
+        let app = axum::Router::new().fallback(|uri: axum::http::Uri| async move {
+            let pause = Duration::from_millis(300);
+            if uri.path().ends_with(":streamGenerateContent") {
+                let events = futures_util::stream::unfold(0, move |i| async move {
+                    tokio::time::sleep(pause).await;
+                    let event = match i {
+                        5 => "data: [DONE]\n\n".to_string(),
+                        6 => return None,
+                        _ => format!("data: {}\n\n", ok_body()),
+                    };
+                    Some((Ok::<_, std::convert::Infallible>(event), i + 1))
+                });
+                axum::body::Body::from_stream(events).into_response()
+            } else {
+                tokio::time::sleep(pause * 5).await;
+                axum::Json(ok_body()).into_response()
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base_url
+    }
+
+    #[tokio::test]
+    async fn only_limits_the_total_time_of_non_streaming_calls() {
+        let upstream = StandIn { base_url: slow_upstream().await, requests: Default::default() };
+        let state = upstream.state(vec![account("a")], |config| config.upstream.request_timeout_secs = 1);
+
+        let mut streaming = request("m");
+        streaming.stream = Some(true);
+        let res = proxy_request(&state, streaming).await.unwrap();
+        let body = res.response.text().await.unwrap();
+        assert!(body.ends_with("data: [DONE]\n\n"), "{}", body);
+
+        let Err(error) = proxy_request(&state, request("m")).await else { panic!("request did not time out") };
+        assert!(error.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout()), "{:#}", error);
+    }
+
+    #[test]
+    fn refuses_non_public_image_hosts() {
+        let check = |url: &str| check_public_url(&url::Url::parse(url).unwrap()).is_ok();
+        assert!(check("https://example.com/a.png"));
+        assert!(check("http://8.8.8.8/a.png"));
+        assert!(!check("http://127.0.0.1/a.png"));
+        assert!(!check("http://169.254.169.254/latest/meta-data"));
+        assert!(!check("http://10.1.2.3/a.png"));
+        assert!(!check("http://192.168.0.1/a.png"));
+        assert!(!check("http://[::1]/a.png"));
+        assert!(!check("http://[::ffff:127.0.0.1]/a.png"));
+        assert!(!check("http://[fd00::1]/a.png"));
+    }
 }
@@ -1,7 +1,7 @@
 use axum::{
     extract::State,
-    http::StatusCode,
-    response::{sse::{Event, Sse}, IntoResponse, Json},
+    middleware,
+    response::{sse::{Event, Sse}, AppendHeaders, IntoResponse, Json},
     routing::{get, post},
     Router,
 };
@@ -9,25 +9,58 @@ use futures_util::StreamExt;
 use serde_json::{json, Value};
 use std::sync::Arc;
 use tower_http::cors::CorsLayer;
-use crate::proxy::{proxy_request, get_auth};
-use crate::transform::{OpenAIRequest, transform_gemini_to_openai, GeminiResponse};
+use crate::api_keys::require_api_key;
+use crate::auth::AuthManager;
+use crate::config::{load_config, BlockedPromptPolicy, Config};
+use crate::error::ApiError;
+use crate::proxy::{proxy_request, UpstreamClient};
+use crate::sse::decode_stream;
+use crate::transform::stream::StreamTransformer;
+use crate::transform::{
+    OpenAIRequest, OpenAIResponse, GeminiResponse, transform_gemini_to_openai,
+    limit_to_single_tool_call, strict_response_schema, validate_response_schema,
+};
+use std::collections::VecDeque;
 use std::convert::Infallible;
 
 pub struct ServerState {
     pub port: u16,
     pub host: String,
+    /// Configuration as loaded at startup.
+    pub config: Config,
+    pub auth: Arc<AuthManager>,
+    pub upstream: UpstreamClient,
 }
 
 pub async fn start_server(host: &str, port: u16) -> anyhow::Result<()> {
+    let config = load_config()?;
+    let upstream = UpstreamClient::from_config(&config.upstream)?;
+    let auth = Arc::new(AuthManager::from_config(&config, upstream.client.clone()));
+    // Fail fast on unusable credentials and refresh expired tokens up front.
+    auth.check_accounts().await?;
+    auth.spawn_refresh_task();
+
+    if config.api_keys.is_empty() && !is_loopback(host) {
+        log::warn!("Listening on {} without API keys; anyone who can reach it can use your quota. Create one with 'gemini-proxy keys create'.", host);
+    }
+
     let state = Arc::new(ServerState {
         port,
         host: host.to_string(),
+        config,
+        auth,
+        upstream,
     });
 
-    let app = Router::new()
-        .route("/health", get(health_check))
+    let api = Router::new()
         .route("/v1/models", get(list_models))
         .route("/v1/chat/completions", post(chat_completions))
+        .route("/health/accounts", get(account_status))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key));
+
+    let app = Router::new()
+        .route("/health", get(health_check))
+        .merge(api)
         .layer(CorsLayer::permissive())
         .with_state(state);
 
@@ -43,14 +76,25 @@ pub async fn start_server(host: &str, port: u16) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn health_check() -> impl IntoResponse {
-    let authenticated = match get_auth().await {
-        Ok(_) => true,
-        Err(_) => false,
-    };
+fn is_loopback(host: &str) -> bool {
+    host == "localhost" || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Open to everyone, so it only reports cached state and never contacts
+/// Google.
+async fn health_check(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let mut authenticated = false;
+    for account in state.auth.accounts() {
+        authenticated |= account.has_valid_token().await;
+    }
     Json(json!({ "status": "ok", "authenticated": authenticated }))
 }
 
+/// Accounts and their cooldowns; behind the API keys like `/v1`.
+async fn account_status(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    Json(json!({ "accounts": state.auth.status() }))
+}
+
 async fn list_models() -> impl IntoResponse {
     Json(json!({
         "object": "list",
@@ -63,78 +107,291 @@ async fn list_models() -> impl IntoResponse {
     }))
 }
 
+/// Set on responses that were answered by a fallback model.
+const MODEL_FALLBACK_HEADER: &str = "x-model-fallback";
+
+/// Extra attempts made when a strict `json_schema` response fails validation.
+const STRICT_SCHEMA_RETRIES: usize = 1;
+
 async fn chat_completions(
-    State(_state): State<Arc<ServerState>>,
+    State(state): State<Arc<ServerState>>,
     Json(payload): Json<OpenAIRequest>,
 ) -> impl IntoResponse {
-    let model = payload.model.clone();
-    let stream = payload.stream.unwrap_or(false);
-
-    match proxy_request(payload).await {
-        Ok(res) => {
-            if stream {
-                let model_clone = model.clone();
-                let stream = res.bytes_stream().flat_map(move |chunk| {
-                    let model_inner = model_clone.clone();
-                    match chunk {
-                        Ok(bytes) => {
-                            let text = String::from_utf8_lossy(&bytes);
-                            let mut events: Vec<Result<Event, Infallible>> = Vec::new();
-                            for line in text.lines() {
-                                let trimmed = line.trim();
-                                if trimmed.starts_with("data: ") {
-                                    let data = &trimmed[6..];
-                                    if data == "[DONE]" {
-                                        // We'll send [DONE] as is or let the client handle it
-                                        continue;
-                                    }
-                                    if let Ok(gemini_res) = serde_json::from_str::<Value>(data) {
-                                        let inner = gemini_res.get("response").unwrap_or(&gemini_res);
-                                        if let Ok(gemini_response) = serde_json::from_value::<GeminiResponse>(inner.clone()) {
-                                            let openai_res = transform_gemini_to_openai(&gemini_response, &model_inner);
-                                            for choice in openai_res.choices {
-                                                if let Some(content) = choice.message.content {
-                                                    let chunk = json!({
-                                                        "id": openai_res.id,
-                                                        "object": "chat.completion.chunk",
-                                                        "created": openai_res.created,
-                                                        "model": openai_res.model,
-                                                        "choices": [{
-                                                            "index": choice.index,
-                                                            "delta": { "content": content },
-                                                            "finish_reason": choice.finish_reason
-                                                        }]
-                                                    });
-                                                    events.push(Ok(Event::default().data(chunk.to_string())));
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            tokio_stream::iter(events)
-                        }
-                        Err(_) => tokio_stream::iter(vec![Ok(Event::default().data("{\"error\": \"Stream error\"}"))]),
+    if payload.stream.unwrap_or(false) {
+        let mut transformer = StreamTransformer::new(&payload);
+        let requested_model = payload.model.clone();
+        return match proxy_request(&state, payload).await {
+            Ok(res) => {
+                transformer.set_model(&res.model);
+                let fallback = fallback_header(&requested_model, &res.model);
+                match stream_completion(res.response, transformer, state.config.blocked_prompts).await {
+                    Ok(stream) => (fallback, stream).into_response(),
+                    Err(e) => e.into_response(),
+                }
+            }
+            Err(e) => ApiError::from(e).into_response(),
+        };
+    }
+
+    let requested_model = payload.model.clone();
+    match complete(&state, payload).await {
+        Ok(openai_res) => {
+            let fallback = fallback_header(&requested_model, &openai_res.model);
+            (fallback, Json(openai_res)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Records which model answered when it was not the one requested.
+fn fallback_header(requested: &str, actual: &str) -> AppendHeaders<Vec<(&'static str, String)>> {
+    if requested == actual {
+        return AppendHeaders(Vec::new());
+    }
+    AppendHeaders(vec![(MODEL_FALLBACK_HEADER, format!("{} -> {}", requested, actual))])
+}
+
+async fn complete(state: &ServerState, payload: OpenAIRequest) -> Result<OpenAIResponse, ApiError> {
+    let strict_schema = strict_response_schema(&payload);
+    let mut attempt = 0;
+
+    loop {
+        let res = proxy_request(state, payload.clone()).await?;
+        let mut openai_res = parse_completion(res.response, &res.model, state.config.blocked_prompts).await?;
+        if payload.parallel_tool_calls == Some(false) {
+            limit_to_single_tool_call(&mut openai_res);
+        }
+
+        let Some(schema) = &strict_schema else {
+            return Ok(openai_res);
+        };
+        match validate_response_schema(&openai_res, schema) {
+            Ok(()) => return Ok(openai_res),
+            Err(e) if attempt < STRICT_SCHEMA_RETRIES => {
+                log::warn!("Response did not match json_schema ({}), retrying", e);
+                attempt += 1;
+            }
+            Err(e) => return Err(ApiError::response_schema_mismatch(e)),
+        }
+    }
+}
+
+async fn parse_completion(
+    res: reqwest::Response,
+    model: &str,
+    blocked_prompts: BlockedPromptPolicy,
+) -> Result<OpenAIResponse, ApiError> {
+    let gemini_res = res.json::<Value>().await
+        .map_err(|e| ApiError::internal(format!("Failed to get JSON from Gemini: {}", e)))?;
+    let inner = gemini_res.get("response").unwrap_or(&gemini_res);
+    let gemini_response = serde_json::from_value::<GeminiResponse>(inner.clone())
+        .map_err(|e| ApiError::internal(format!("Failed to parse Gemini response: {}", e)))?;
+    if let Some(message) = gemini_response.block_message() {
+        if blocked_prompts == BlockedPromptPolicy::Error {
+            return Err(ApiError::content_filter(message));
+        }
+    }
+    Ok(transform_gemini_to_openai(&gemini_response, model))
+}
+
+/// Streams the completion. With `blockedPrompts: "error"`, the first event
+/// is read before the headers go out, so a blocked prompt can still become
+/// a 400 error.
+async fn stream_completion(
+    res: reqwest::Response,
+    transformer: StreamTransformer,
+    blocked_prompts: BlockedPromptPolicy,
+) -> Result<impl IntoResponse, ApiError> {
+    struct State<S> {
+        events: S,
+        transformer: StreamTransformer,
+        pending: VecDeque<Event>,
+        done: bool,
+    }
+
+    let mut events = Box::pin(decode_stream(res.bytes_stream()));
+    let mut first = None;
+    if blocked_prompts == BlockedPromptPolicy::Error {
+        first = events.next().await;
+        let blocked = match &first {
+            Some(Ok(event)) => parse_stream_event(&event.data).ok().flatten().and_then(|r| r.block_message()),
+            _ => None,
+        };
+        if let Some(message) = blocked {
+            return Err(ApiError::content_filter(message));
+        }
+    }
+
+    let state = State {
+        events: futures_util::stream::iter(first).chain(events),
+        transformer,
+        pending: VecDeque::new(),
+        done: false,
+    };
+
+    let stream = futures_util::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((Ok::<_, Infallible>(event), state));
+            }
+            if state.done {
+                return None;
+            }
+            match state.events.next().await {
+                Some(Ok(event)) => match parse_stream_event(&event.data) {
+                    Ok(Some(gemini_response)) => {
+                        let chunks = state.transformer.push(&gemini_response);
+                        state.pending.extend(chunks.into_iter().map(|c| Event::default().data(c.to_string())));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        state.done = true;
+                        state.pending.push_back(Event::default().data(e.body().to_string()));
                     }
-                });
-
-                Sse::new(stream).into_response()
-            } else {
-                match res.json::<Value>().await {
-                    Ok(gemini_res) => {
-                        let inner = gemini_res.get("response").unwrap_or(&gemini_res);
-                        match serde_json::from_value::<GeminiResponse>(inner.clone()) {
-                            Ok(gemini_response) => {
-                                let openai_res = transform_gemini_to_openai(&gemini_response, &model);
-                                Json(openai_res).into_response()
-                            }
-                            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Failed to parse Gemini response: {}", e) }))).into_response()
+                },
+                Some(Err(e)) => {
+                    // Headers are already sent, so the failure can only be
+                    // reported as a terminal error event.
+                    log::warn!("Upstream stream failed: {}", e);
+                    state.done = true;
+                    let error = ApiError::internal(format!("Upstream stream failed: {}", e));
+                    state.pending.push_back(Event::default().data(error.body().to_string()));
+                }
+                None => {
+                    state.done = true;
+                    match state.transformer.finish() {
+                        Ok(chunks) => {
+                            state.pending.extend(chunks.into_iter().map(|c| Event::default().data(c.to_string())));
+                            state.pending.push_back(Event::default().data("[DONE]"));
+                        }
+                        Err(e) => {
+                            log::warn!("Streamed response did not match json_schema: {}", e);
+                            state.pending.push_back(Event::default().data(e.body().to_string()));
                         }
                     }
-                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Failed to get JSON from Gemini: {}", e) }))).into_response()
                 }
             }
         }
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))).into_response(),
+    });
+
+    Ok(Sse::new(stream))
+}
+
+/// Parses one upstream SSE `data` payload, unwrapping the Code Assist envelope.
+/// An embedded `google.rpc.Status` becomes an error.
+fn parse_stream_event(data: &str) -> Result<Option<GeminiResponse>, ApiError> {
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+    let Ok(gemini_res) = serde_json::from_str::<Value>(data) else {
+        return Ok(None);
+    };
+    if let Some(error) = gemini_res.get("error") {
+        let code = error["code"].as_u64().unwrap_or(500) as u16;
+        return Err(ApiError::from_upstream(code, data));
+    }
+    let inner = gemini_res.get("response").unwrap_or(&gemini_res);
+    Ok(serde_json::from_value::<GeminiResponse>(inner.clone()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::tests::{account, ok_body, request, StandIn};
+
+    fn blocked() -> Value {
+        json!({ "promptFeedback": { "blockReason": "SAFETY" } })
+    }
+
+    fn upstream_response(body: String) -> reqwest::Response {
+        reqwest::Response::from(axum::http::Response::new(body))
+    }
+
+    async fn body_of(response: impl IntoResponse) -> String {
+        let body = axum::body::to_bytes(response.into_response().into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn blocked_prompts_become_refusals_or_errors() {
+        let body = json!({ "response": blocked() }).to_string();
+
+        let response = parse_completion(upstream_response(body.clone()), "m", BlockedPromptPolicy::Refusal).await.unwrap();
+        assert_eq!(response.choices[0].message.refusal.as_deref(), Some("The prompt was blocked (SAFETY)"));
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("content_filter"));
+
+        let error = parse_completion(upstream_response(body), "m", BlockedPromptPolicy::Error).await.unwrap_err();
+        assert_eq!(error.status.as_u16(), 400);
+        assert_eq!(error.code.as_deref(), Some("content_filter"));
+    }
+
+    #[tokio::test]
+    async fn blocked_streams_fail_before_the_headers_under_the_error_policy() {
+        let events = format!("data: {}\n\n", json!({ "response": blocked() }));
+        let transformer = || StreamTransformer::new(&request("m"));
+
+        let Err(error) = stream_completion(upstream_response(events.clone()), transformer(), BlockedPromptPolicy::Error).await else {
+            panic!("blocked stream was sent");
+        };
+        assert_eq!(error.status.as_u16(), 400);
+        assert_eq!(error.code.as_deref(), Some("content_filter"));
+
+        let Ok(stream) = stream_completion(upstream_response(events), transformer(), BlockedPromptPolicy::Refusal).await else {
+            panic!("blocked stream failed");
+        };
+        let body = body_of(stream).await;
+        assert!(body.contains(r#""refusal":"The prompt was blocked (SAFETY)""#), "{}", body);
+        assert!(body.contains(r#""finish_reason":"content_filter""#), "{}", body);
+        assert!(body.ends_with("data: [DONE]\n\n"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn streams_unblocked_responses_under_the_error_policy() {
+        let events = format!("data: {}\n\n", json!({ "response": ok_body()["response"] }));
+        let Ok(stream) = stream_completion(upstream_response(events), StreamTransformer::new(&request("m")), BlockedPromptPolicy::Error).await else {
+            panic!("stream failed");
+        };
+        let body = body_of(stream).await;
+        assert!(body.contains(r#""content":"ok""#), "{}", body);
+        assert!(body.ends_with("data: [DONE]\n\n"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn names_the_fallback_model_that_answered() {
+        let upstream = StandIn::start(|model, _| match model {
+            "primary" => (429, json!({ "error": { "code": 429, "message": "quota", "status": "RESOURCE_EXHAUSTED" } })),
+            _ => (200, ok_body()),
+        }).await;
+        let state = Arc::new(upstream.state(vec![account("a")], |config| {
+            config.model_fallbacks.insert("primary".to_string(), vec!["secondary".to_string()]);
+        }));
+
+        let res = chat_completions(State(state.clone()), Json(request("primary"))).await.into_response();
+        assert_eq!(res.headers()[MODEL_FALLBACK_HEADER], "primary -> secondary");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["model"], "secondary");
+
+        let res = chat_completions(State(state), Json(request("secondary"))).await.into_response();
+        assert!(res.headers().get(MODEL_FALLBACK_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_one_tool_call_without_parallel_tool_calls() {
+        let upstream = StandIn::start(|_, _| (200, json!({ "response": { "candidates": [{
+            "content": { "role": "model", "parts": [
+                { "functionCall": { "name": "weather", "args": {} } },
+                { "functionCall": { "name": "time", "args": {} } },
+            ] },
+            "finishReason": "STOP",
+        }] } }))).await;
+        let state = upstream.state(vec![account("a")], |_| {});
+
+        let mut payload = request("m");
+        payload.parallel_tool_calls = Some(false);
+        let response = complete(&state, payload).await.unwrap();
+        let tool_calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].function.name, "weather");
     }
 }
@@ -0,0 +1,245 @@
+use futures_util::{Stream, StreamExt};
+use std::collections::VecDeque;
+
+/// A server-sent event as received from upstream.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+}
+
+/// Incremental SSE decoder. Bytes can be pushed in arbitrary chunks: lines
+/// are only decoded once complete, so events and multibyte UTF-8 characters
+/// split across chunk boundaries come out intact.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    data_lines: Vec<String>,
+    event: Option<String>,
+    id: Option<String>,
+    started: bool,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a chunk of bytes and returns the events it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        if !self.started {
+            if self.buffer.len() < 3 && b"\xEF\xBB\xBF".starts_with(&self.buffer) {
+                return Vec::new();
+            }
+            if self.buffer.starts_with(b"\xEF\xBB\xBF") {
+                self.buffer.drain(..3);
+            }
+            self.started = true;
+        }
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buffer.len() {
+            let terminator_len = match self.buffer[i] {
+                b'\n' => 1,
+                b'\r' => match self.buffer.get(i + 1) {
+                    Some(b'\n') => 2,
+                    Some(_) => 1,
+                    // A trailing CR may be the first half of a CRLF.
+                    None => break,
+                },
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let line = String::from_utf8_lossy(&self.buffer[start..i]).into_owned();
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+            i += terminator_len;
+            start = i;
+        }
+        self.buffer.drain(..start);
+        events
+    }
+
+    /// Flushes an event left unterminated when the stream ends.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buffer.is_empty() {
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.buffer)).into_owned();
+            if let Some(event) = self.process_line(&line) {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => self.data_lines.push(value.to_string()),
+            "event" => self.event = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data_lines.is_empty() {
+            return None;
+        }
+        Some(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data_lines).join("\n"),
+            id: self.id.clone(),
+        })
+    }
+}
+
+/// Decodes a byte stream into SSE events.
+pub fn decode_stream<S, B, E>(stream: S) -> impl Stream<Item = Result<SseEvent, E>>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    struct State<S> {
+        stream: S,
+        decoder: SseDecoder,
+        pending: VecDeque<SseEvent>,
+        done: bool,
+    }
+
+    let state = State {
+        stream,
+        decoder: SseDecoder::new(),
+        pending: VecDeque::new(),
+        done: false,
+    };
+
+    futures_util::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((Ok(event), state));
+            }
+            if state.done {
+                return None;
+            }
+            match state.stream.next().await {
+                Some(Ok(chunk)) => {
+                    let events = state.decoder.push(chunk.as_ref());
+                    state.pending.extend(events);
+                }
+                Some(Err(e)) => {
+                    state.done = true;
+                    return Some((Err(e), state));
+                }
+                None => {
+                    state.done = true;
+                    state.pending.extend(state.decoder.finish());
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_in_chunks(input: &[u8], chunk_size: usize) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events = Vec::new();
+        for chunk in input.chunks(chunk_size) {
+            events.extend(decoder.push(chunk));
+        }
+        events.extend(decoder.finish());
+        events
+    }
+
+    fn data(events: &[SseEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.data.as_str()).collect()
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let input = "data: {\"a\":1}\n\ndata: {\"b\":2}\n\n".as_bytes();
+        for size in 1..=input.len() {
+            let events = decode_in_chunks(input, size);
+            assert_eq!(data(&events), vec!["{\"a\":1}", "{\"b\":2}"], "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn keeps_multibyte_characters_split_across_chunks() {
+        let input = "data: héllo 🌍 世界\n\n".as_bytes();
+        for size in 1..=input.len() {
+            let events = decode_in_chunks(input, size);
+            assert_eq!(data(&events), vec!["héllo 🌍 世界"], "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn handles_crlf_split_between_chunks() {
+        let input = b"data: one\r\n\r\ndata: two\r\n\r\n";
+        for size in 1..=input.len() {
+            let events = decode_in_chunks(input, size);
+            assert_eq!(data(&events), vec!["one", "two"], "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn joins_multi_line_data_and_skips_comments() {
+        let input = b": keep-alive\nevent: message\nid: 7\ndata: first\ndata:second\n\n";
+        let events = decode_in_chunks(input, 4);
+        assert_eq!(events, vec![SseEvent {
+            event: Some("message".to_string()),
+            data: "first\nsecond".to_string(),
+            id: Some("7".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn ignores_events_without_data() {
+        let input = b"event: ping\n\n: comment\n\ndata: x\n\n";
+        assert_eq!(data(&decode_in_chunks(input, 1)), vec!["x"]);
+    }
+
+    #[test]
+    fn flushes_unterminated_event_at_end() {
+        let input = b"data: tail";
+        assert_eq!(data(&decode_in_chunks(input, 3)), vec!["tail"]);
+    }
+
+    #[test]
+    fn strips_leading_bom() {
+        let input = b"\xEF\xBB\xBFdata: x\n\n";
+        for size in 1..=input.len() {
+            assert_eq!(data(&decode_in_chunks(input, size)), vec!["x"], "chunk size {}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_a_chunked_stream() {
+        let input = "data: {\"text\":\"ü\"}\n\ndata: [DONE]\n\n".as_bytes();
+        let chunks: Vec<Result<Vec<u8>, ()>> = input.chunks(1).map(|c| Ok(c.to_vec())).collect();
+        let events: Vec<_> = decode_stream(futures_util::stream::iter(chunks)).collect().await;
+        let events: Vec<SseEvent> = events.into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(data(&events), vec!["{\"text\":\"ü\"}", "[DONE]"]);
+    }
+}
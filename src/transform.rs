@@ -1,15 +1,69 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use anyhow::Result;
+use std::collections::HashMap;
+use crate::config::{Config, SystemMessagePolicy};
 use crate::constants::GEMINI_CODE_ASSIST_ENDPOINT;
+use crate::error::ApiError;
+
+pub mod schema;
+pub mod signatures;
+pub mod stream;
+pub mod thinking;
+
+use thinking::{GeminiThinkingConfig, OpenAIThinkingConfig};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct OpenAIMessage {
     pub role: String,
-    pub content: Option<String>,
+    pub content: Option<OpenAIContent>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<OpenAIToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+}
+
+/// Message content: either a plain string or an array of content parts.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum OpenAIContent {
+    Text(String),
+    Parts(Vec<OpenAIContentPart>),
+}
+
+impl OpenAIContent {
+    /// Concatenated text of the content, ignoring non-text parts.
+    pub fn text(&self) -> String {
+        match self {
+            OpenAIContent::Text(text) => text.clone(),
+            OpenAIContent::Parts(parts) => parts.iter().filter_map(|p| match p {
+                OpenAIContentPart::Text { text } => Some(text.as_str()),
+                _ => None,
+            }).collect::<Vec<_>>().join(""),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpenAIContentPart {
+    Text { text: String },
+    ImageUrl { image_url: OpenAIImageUrl },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAIImageUrl {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -30,9 +84,83 @@ pub struct OpenAIRequest {
     pub model: String,
     pub messages: Vec<OpenAIMessage>,
     pub stream: Option<bool>,
+    pub stream_options: Option<OpenAIStreamOptions>,
     pub temperature: Option<f32>,
     pub max_tokens: Option<u32>,
+    pub max_completion_tokens: Option<u32>,
+    pub top_p: Option<f32>,
+    /// Not part of the OpenAI API; passed through to Gemini's `topK`.
+    pub top_k: Option<u32>,
+    pub stop: Option<OpenAIStop>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub seed: Option<i64>,
+    pub n: Option<u32>,
+    pub reasoning_effort: Option<String>,
+    /// Not part of the OpenAI API; sets Gemini's `thinkingConfig` directly.
+    pub thinking_config: Option<OpenAIThinkingConfig>,
     pub tools: Option<Vec<OpenAITool>>,
+    pub tool_choice: Option<OpenAIToolChoice>,
+    pub parallel_tool_calls: Option<bool>,
+    pub response_format: Option<OpenAIResponseFormat>,
+}
+
+/// `stop`: a single sequence or a list of them.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum OpenAIStop {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl OpenAIStop {
+    pub fn sequences(&self) -> Vec<String> {
+        match self {
+            OpenAIStop::Single(s) => vec![s.clone()],
+            OpenAIStop::Multiple(v) => v.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpenAIResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: OpenAIJsonSchema },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAIJsonSchema {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAIStreamOptions {
+    pub include_usage: Option<bool>,
+}
+
+/// `tool_choice`: either a mode string (`auto`, `none`, `required`) or a
+/// specific function.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum OpenAIToolChoice {
+    Mode(String),
+    Function {
+        r#type: String,
+        function: OpenAIToolChoiceFunction,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAIToolChoiceFunction {
+    pub name: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -48,18 +176,39 @@ pub struct OpenAIFunctionDefinition {
     pub parameters: Option<Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct GeminiContentPart {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub text: Option<String>,
+    #[serde(rename = "inlineData", skip_serializing_if = "Option::is_none")]
+    pub inline_data: Option<GeminiInlineData>,
+    #[serde(rename = "fileData", skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<GeminiFileData>,
     #[serde(rename = "functionCall", skip_serializing_if = "Option::is_none")]
     pub function_call: Option<GeminiFunctionCall>,
     #[serde(rename = "functionResponse", skip_serializing_if = "Option::is_none")]
     pub function_response: Option<GeminiFunctionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thought: Option<bool>,
     #[serde(rename = "thoughtSignature", skip_serializing_if = "Option::is_none")]
     pub thought_signature: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeminiInlineData {
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    pub data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeminiFileData {
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    #[serde(rename = "fileUri")]
+    pub file_uri: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GeminiFunctionCall {
     pub name: String,
@@ -84,6 +233,26 @@ pub struct GeminiGenerationConfig {
     pub temperature: Option<f32>,
     #[serde(rename = "maxOutputTokens", skip_serializing_if = "Option::is_none")]
     pub max_output_tokens: Option<u32>,
+    #[serde(rename = "topP", skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(rename = "topK", skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(rename = "stopSequences", skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(rename = "presencePenalty", skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(rename = "frequencyPenalty", skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i32>,
+    #[serde(rename = "candidateCount", skip_serializing_if = "Option::is_none")]
+    pub candidate_count: Option<u32>,
+    #[serde(rename = "responseMimeType", skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+    #[serde(rename = "responseSchema", skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+    #[serde(rename = "thinkingConfig", skip_serializing_if = "Option::is_none")]
+    pub thinking_config: Option<GeminiThinkingConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -99,6 +268,19 @@ pub struct GeminiFunctionDeclaration {
     pub parameters: Option<Value>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeminiToolConfig {
+    #[serde(rename = "functionCallingConfig")]
+    pub function_calling_config: GeminiFunctionCallingConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeminiFunctionCallingConfig {
+    pub mode: String,
+    #[serde(rename = "allowedFunctionNames", skip_serializing_if = "Option::is_none")]
+    pub allowed_function_names: Option<Vec<String>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GeminiRequest {
     pub contents: Vec<GeminiContent>,
@@ -106,6 +288,10 @@ pub struct GeminiRequest {
     pub generation_config: Option<GeminiGenerationConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tools: Option<Vec<GeminiTool>>,
+    #[serde(rename = "toolConfig", skip_serializing_if = "Option::is_none")]
+    pub tool_config: Option<GeminiToolConfig>,
+    #[serde(rename = "systemInstruction", skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<GeminiContent>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -115,35 +301,329 @@ pub struct GeminiWrappedRequest {
     pub request: GeminiRequest,
 }
 
-pub fn transform_openai_to_gemini(request: &OpenAIRequest, project_id: &str) -> (String, GeminiWrappedRequest, bool) {
+/// Proxy-level settings that influence how requests are translated.
+#[derive(Debug, Clone)]
+pub struct TransformOptions {
+    pub system_messages: SystemMessagePolicy,
+    /// Base URL of the Code Assist API.
+    pub endpoint: String,
+}
+
+impl Default for TransformOptions {
+    fn default() -> Self {
+        TransformOptions {
+            system_messages: SystemMessagePolicy::default(),
+            endpoint: GEMINI_CODE_ASSIST_ENDPOINT.to_string(),
+        }
+    }
+}
+
+impl TransformOptions {
+    pub fn from_config(config: &Config) -> Self {
+        TransformOptions {
+            system_messages: config.system_messages,
+            endpoint: config.upstream.base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+fn is_system_role(role: &str) -> bool {
+    matches!(role, "system" | "developer")
+}
+
+/// Splits a `data:` URL into its mime type and base64 payload.
+pub fn parse_data_url(url: &str) -> Option<(String, String)> {
+    use base64::Engine;
+
+    let rest = url.strip_prefix("data:")?;
+    let (meta, payload) = rest.split_once(',')?;
+    let mut meta_parts = meta.split(';');
+    let mime_type = match meta_parts.next() {
+        Some(m) if !m.is_empty() => m.to_string(),
+        _ => "text/plain".to_string(),
+    };
+    if meta_parts.any(|p| p == "base64") {
+        return Some((mime_type, payload.to_string()));
+    }
+
+    // Non-base64 payloads are percent-encoded.
+    let bytes = payload.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some((mime_type, base64::engine::general_purpose::STANDARD.encode(decoded)))
+}
+
+/// Best-effort mime type for a URL based on its file extension.
+pub fn guess_mime_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let ext = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn image_url_to_part(url: &str) -> Option<GeminiContentPart> {
+    if url.starts_with("data:") {
+        let (mime_type, data) = parse_data_url(url)?;
+        return Some(GeminiContentPart {
+            inline_data: Some(GeminiInlineData { mime_type, data }),
+            ..Default::default()
+        });
+    }
+
+    Some(GeminiContentPart {
+        file_data: Some(GeminiFileData {
+            mime_type: guess_mime_type(url).to_string(),
+            file_uri: url.to_string(),
+        }),
+        ..Default::default()
+    })
+}
+
+/// Gemini expects a function response to be an object, so JSON objects are
+/// passed through and anything else is wrapped as `{"result": ...}`.
+fn tool_result_to_response(content: Option<&OpenAIContent>) -> Value {
+    let text = content.map(|c| c.text()).unwrap_or_default();
+    match serde_json::from_str::<Value>(&text) {
+        Ok(Value::Object(obj)) => Value::Object(obj),
+        Ok(value) => serde_json::json!({ "result": value }),
+        Err(_) => serde_json::json!({ "result": text }),
+    }
+}
+
+/// Most stop sequences Gemini accepts.
+const MAX_STOP_SEQUENCES: usize = 5;
+/// Most candidates Gemini generates per request.
+const MAX_CANDIDATE_COUNT: u32 = 8;
+
+fn check_range(value: Option<f32>, min: f32, max: f32, param: &str) -> Result<()> {
+    match value {
+        Some(v) if !(min..=max).contains(&v) => Err(ApiError::invalid_request(
+            format!("{} must be between {} and {}, got {}", param, min, max, v),
+            Some(param),
+        ).into()),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects sampling parameters Gemini would refuse, so clients get a clear
+/// 400 instead of an opaque upstream failure.
+fn validate_sampling(request: &OpenAIRequest) -> Result<()> {
+    check_range(request.temperature, 0.0, 2.0, "temperature")?;
+    check_range(request.top_p, 0.0, 1.0, "top_p")?;
+    check_range(request.presence_penalty, -2.0, 2.0, "presence_penalty")?;
+    check_range(request.frequency_penalty, -2.0, 2.0, "frequency_penalty")?;
+
+    if request.top_k == Some(0) {
+        return Err(ApiError::invalid_request("top_k must be at least 1", Some("top_k")).into());
+    }
+    for (value, param) in [(request.max_tokens, "max_tokens"), (request.max_completion_tokens, "max_completion_tokens")] {
+        if value == Some(0) {
+            return Err(ApiError::invalid_request(format!("{} must be at least 1", param), Some(param)).into());
+        }
+    }
+    if let Some(n) = request.n {
+        if !(1..=MAX_CANDIDATE_COUNT).contains(&n) {
+            return Err(ApiError::invalid_request(
+                format!("n must be between 1 and {}, got {}", MAX_CANDIDATE_COUNT, n),
+                Some("n"),
+            ).into());
+        }
+    }
+    if let Some(seed) = request.seed {
+        if i32::try_from(seed).is_err() {
+            return Err(ApiError::invalid_request("seed must fit in a 32-bit signed integer", Some("seed")).into());
+        }
+    }
+    if let Some(effort) = &request.reasoning_effort {
+        if !thinking::REASONING_EFFORTS.contains(&effort.as_str()) {
+            return Err(ApiError::invalid_request(
+                format!("Invalid reasoning_effort '{}': expected one of {}", effort, thinking::REASONING_EFFORTS.join(", ")),
+                Some("reasoning_effort"),
+            ).into());
+        }
+    }
+    if let Some(stop) = &request.stop {
+        if stop.sequences().len() > MAX_STOP_SEQUENCES {
+            return Err(ApiError::invalid_request(
+                format!("stop supports at most {} sequences", MAX_STOP_SEQUENCES),
+                Some("stop"),
+            ).into());
+        }
+    }
+    Ok(())
+}
+
+fn transform_tool_choice(request: &OpenAIRequest) -> Result<Option<GeminiToolConfig>> {
+    let Some(choice) = &request.tool_choice else {
+        return Ok(None);
+    };
+
+    let (mode, allowed_function_names) = match choice {
+        OpenAIToolChoice::Mode(mode) => match mode.as_str() {
+            "auto" => ("AUTO", None),
+            "none" => ("NONE", None),
+            "required" => ("ANY", None),
+            other => {
+                return Err(ApiError::invalid_request(
+                    format!("Invalid tool_choice '{}': expected 'auto', 'none' or 'required'", other),
+                    Some("tool_choice"),
+                ).into());
+            }
+        },
+        OpenAIToolChoice::Function { function, .. } => {
+            let declared = request.tools.iter().flatten().any(|t| t.function.name == function.name);
+            if !declared {
+                return Err(ApiError::invalid_request(
+                    format!("tool_choice references unknown function '{}'", function.name),
+                    Some("tool_choice"),
+                ).into());
+            }
+            ("ANY", Some(vec![function.name.clone()]))
+        }
+    };
+
+    // A tool config without any declared tools is rejected upstream.
+    if request.tools.as_ref().is_none_or(|t| t.is_empty()) {
+        return Ok(None);
+    }
+
+    Ok(Some(GeminiToolConfig {
+        function_calling_config: GeminiFunctionCallingConfig {
+            mode: mode.to_string(),
+            allowed_function_names,
+        },
+    }))
+}
+
+fn content_to_parts(content: &OpenAIContent, msg_index: usize) -> Result<Vec<GeminiContentPart>> {
+    let parts = match content {
+        OpenAIContent::Text(text) => return Ok(vec![GeminiContentPart {
+            text: Some(text.clone()),
+            ..Default::default()
+        }]),
+        OpenAIContent::Parts(parts) => parts,
+    };
+
+    let mut result = Vec::new();
+    for (j, part) in parts.iter().enumerate() {
+        let param = format!("messages[{}].content[{}]", msg_index, j);
+        match part {
+            OpenAIContentPart::Text { text } => result.push(GeminiContentPart {
+                text: Some(text.clone()),
+                ..Default::default()
+            }),
+            OpenAIContentPart::ImageUrl { image_url } => {
+                let part = image_url_to_part(&image_url.url).ok_or_else(|| {
+                    ApiError::invalid_request("Invalid image_url: malformed data URL", Some(&param))
+                })?;
+                result.push(part);
+            }
+            OpenAIContentPart::Unsupported => {
+                return Err(ApiError::invalid_request("Unsupported content part type", Some(&param)).into());
+            }
+        }
+    }
+    Ok(result)
+}
+
+pub fn transform_openai_to_gemini(request: &OpenAIRequest, project_id: &str, options: &TransformOptions) -> Result<(String, GeminiWrappedRequest, bool)> {
     let streaming = request.stream.unwrap_or(false);
     let action = if streaming { "streamGenerateContent" } else { "generateContent" };
-    let url = format!("{}/v1internal:{}{}", GEMINI_CODE_ASSIST_ENDPOINT, action, if streaming { "?alt=sse" } else { "" });
+    let url = format!("{}/v1internal:{}{}", options.endpoint, action, if streaming { "?alt=sse" } else { "" });
+
+    validate_sampling(request)?;
+
+    let mut contents: Vec<GeminiContent> = Vec::new();
+    let mut system_texts: Vec<String> = Vec::new();
+    let mut tool_call_names: HashMap<&str, &str> = HashMap::new();
+    for (i, msg) in request.messages.iter().enumerate() {
+        if is_system_role(&msg.role) {
+            let text = msg.content.as_ref().map(|c| c.text()).unwrap_or_default();
+            // System messages before the first conversational turn always go
+            // to the system instruction; later ones follow the policy.
+            if contents.is_empty() {
+                system_texts.push(text);
+                continue;
+            }
+            match options.system_messages {
+                SystemMessagePolicy::Hoist => {
+                    system_texts.push(text);
+                    continue;
+                }
+                SystemMessagePolicy::Inline => {}
+                SystemMessagePolicy::Reject => {
+                    return Err(ApiError::invalid_request(
+                        format!("A {} message may only appear at the start of the conversation", msg.role),
+                        Some(&format!("messages[{}]", i)),
+                    ).into());
+                }
+            }
+        }
+
+        if msg.role == "tool" || msg.role == "function" {
+            let name = msg.tool_call_id.as_deref()
+                .and_then(|id| tool_call_names.get(id).copied())
+                .or(msg.name.as_deref())
+                .ok_or_else(|| ApiError::invalid_request(
+                    "Tool message does not match any previous assistant tool call",
+                    Some(&format!("messages[{}].tool_call_id", i)),
+                ))?;
+            let part = GeminiContentPart {
+                function_response: Some(GeminiFunctionResponse {
+                    name: name.to_string(),
+                    response: tool_result_to_response(msg.content.as_ref()),
+                }),
+                ..Default::default()
+            };
+
+            // Results of parallel calls must be sent back together in one turn.
+            match contents.last_mut() {
+                Some(last) if last.role == "user" && last.parts.iter().all(|p| p.function_response.is_some()) => {
+                    last.parts.push(part);
+                }
+                _ => contents.push(GeminiContent {
+                    role: "user".to_string(),
+                    parts: vec![part],
+                }),
+            }
+            continue;
+        }
 
-    let mut contents = Vec::new();
-    for msg in &request.messages {
         let mut parts = Vec::new();
         
         if let Some(content) = &msg.content {
-            parts.push(GeminiContentPart {
-                text: Some(content.clone()),
-                function_call: None,
-                function_response: None,
-                thought_signature: None,
-            });
+            parts.extend(content_to_parts(content, i)?);
         }
         
         if let Some(tool_calls) = &msg.tool_calls {
             for tc in tool_calls {
+                tool_call_names.insert(tc.id.as_str(), tc.function.name.as_str());
                 let args: Value = serde_json::from_str(&tc.function.arguments).unwrap_or(Value::Null);
                 parts.push(GeminiContentPart {
-                    text: None,
                     function_call: Some(GeminiFunctionCall {
                         name: tc.function.name.clone(),
                         args,
                     }),
-                    function_response: None,
-                    thought_signature: Some("skip_thought_signature_validator".to_string()),
+                    thought_signature: signatures::signature_for(&tc.id),
+                    ..Default::default()
                 });
             }
         }
@@ -151,8 +631,6 @@ pub fn transform_openai_to_gemini(request: &OpenAIRequest, project_id: &str) ->
         let role = match msg.role.as_str() {
             "user" => "user",
             "assistant" => "model",
-            "tool" => "function",
-            "system" => continue, // Skip system for now or handle separately
             _ => "user",
         };
         
@@ -162,23 +640,64 @@ pub fn transform_openai_to_gemini(request: &OpenAIRequest, project_id: &str) ->
         });
     }
 
+    let (response_mime_type, response_schema) = match &request.response_format {
+        Some(OpenAIResponseFormat::JsonObject) => (Some("application/json".to_string()), None),
+        Some(OpenAIResponseFormat::JsonSchema { json_schema }) => (
+            Some("application/json".to_string()),
+            json_schema.schema.as_ref()
+                .map(|s| schema::sanitize_schema(s, &format!("response_format '{}'", json_schema.name))),
+        ),
+        Some(OpenAIResponseFormat::Text) | None => (None, None),
+    };
+
     let generation_config = Some(GeminiGenerationConfig {
         temperature: request.temperature,
-        max_output_tokens: request.max_tokens,
+        max_output_tokens: request.max_completion_tokens.or(request.max_tokens),
+        top_p: request.top_p,
+        top_k: request.top_k,
+        stop_sequences: request.stop.as_ref().map(|s| s.sequences()).filter(|s| !s.is_empty()),
+        presence_penalty: request.presence_penalty,
+        frequency_penalty: request.frequency_penalty,
+        seed: request.seed.map(|s| s as i32),
+        candidate_count: request.n,
+        response_mime_type,
+        response_schema,
+        thinking_config: thinking::thinking_config(
+            &request.model,
+            request.reasoning_effort.as_deref(),
+            request.thinking_config.as_ref(),
+        ),
     });
 
     let tools = request.tools.as_ref().map(|t| vec![GeminiTool {
         function_declarations: t.iter().map(|ot| GeminiFunctionDeclaration {
             name: ot.function.name.clone(),
             description: ot.function.description.clone(),
-            parameters: ot.function.parameters.clone(),
+            parameters: ot.function.parameters.as_ref()
+                .map(|p| schema::sanitize_schema(p, &format!("tool '{}'", ot.function.name))),
         }).collect(),
     }]);
 
+    let tool_config = transform_tool_choice(request)?;
+
+    let system_instruction = if system_texts.is_empty() {
+        None
+    } else {
+        Some(GeminiContent {
+            role: "user".to_string(),
+            parts: vec![GeminiContentPart {
+                text: Some(system_texts.join("\n\n")),
+                ..Default::default()
+            }],
+        })
+    };
+
     let gemini_request = GeminiRequest {
         contents,
         generation_config,
         tools,
+        tool_config,
+        system_instruction,
     };
 
     let wrapped = GeminiWrappedRequest {
@@ -187,7 +706,7 @@ pub fn transform_openai_to_gemini(request: &OpenAIRequest, project_id: &str) ->
         request: gemini_request,
     };
 
-    (url, wrapped, streaming)
+    Ok((url, wrapped, streaming))
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -197,11 +716,37 @@ pub struct OpenAIChoice {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct OpenAIUsage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<OpenAIPromptTokensDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<OpenAICompletionTokensDetails>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OpenAIPromptTokensDetails {
+    pub cached_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_tokens: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OpenAICompletionTokensDetails {
+    pub reasoning_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_tokens: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -231,6 +776,76 @@ pub struct GeminiUsageMetadata {
     pub candidates_token_count: Option<u32>,
     #[serde(rename = "totalTokenCount")]
     pub total_token_count: Option<u32>,
+    #[serde(rename = "thoughtsTokenCount")]
+    pub thoughts_token_count: Option<u32>,
+    #[serde(rename = "cachedContentTokenCount")]
+    pub cached_content_token_count: Option<u32>,
+    #[serde(rename = "toolUsePromptTokenCount")]
+    pub tool_use_prompt_token_count: Option<u32>,
+    #[serde(rename = "promptTokensDetails")]
+    pub prompt_tokens_details: Option<Vec<GeminiModalityTokenCount>>,
+    #[serde(rename = "candidatesTokensDetails")]
+    pub candidates_tokens_details: Option<Vec<GeminiModalityTokenCount>>,
+    #[serde(rename = "toolUsePromptTokensDetails")]
+    pub tool_use_prompt_tokens_details: Option<Vec<GeminiModalityTokenCount>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GeminiModalityTokenCount {
+    pub modality: String,
+    #[serde(rename = "tokenCount")]
+    pub token_count: Option<u32>,
+}
+
+/// Sums the token counts for one modality across the given breakdowns.
+fn modality_tokens(breakdowns: &[&Option<Vec<GeminiModalityTokenCount>>], modality: &str) -> Option<u32> {
+    let counts: Vec<u32> = breakdowns.iter()
+        .filter_map(|b| b.as_ref())
+        .flatten()
+        .filter(|c| c.modality == modality)
+        .map(|c| c.token_count.unwrap_or(0))
+        .collect();
+    if counts.is_empty() { None } else { Some(counts.iter().sum()) }
+}
+
+impl GeminiUsageMetadata {
+    /// Converts to OpenAI usage. Tool-use prompt tokens count as prompt
+    /// tokens and thinking tokens as completion tokens, so the parts add up
+    /// to Gemini's total; cached tokens are a subset of the prompt tokens.
+    pub fn to_openai(&self) -> OpenAIUsage {
+        let prompt_tokens = self.prompt_token_count.unwrap_or(0) + self.tool_use_prompt_token_count.unwrap_or(0);
+        let reasoning_tokens = self.thoughts_token_count.unwrap_or(0);
+        let completion_tokens = self.candidates_token_count.unwrap_or(0) + reasoning_tokens;
+
+        let prompt_breakdowns = [&self.prompt_tokens_details, &self.tool_use_prompt_tokens_details];
+        let completion_breakdowns = [&self.candidates_tokens_details];
+
+        OpenAIUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: Some(OpenAIPromptTokensDetails {
+                cached_tokens: self.cached_content_token_count.unwrap_or(0),
+                audio_tokens: modality_tokens(&prompt_breakdowns, "AUDIO"),
+                text_tokens: modality_tokens(&prompt_breakdowns, "TEXT"),
+                image_tokens: modality_tokens(&prompt_breakdowns, "IMAGE"),
+            }),
+            completion_tokens_details: Some(OpenAICompletionTokensDetails {
+                reasoning_tokens,
+                audio_tokens: modality_tokens(&completion_breakdowns, "AUDIO"),
+                text_tokens: modality_tokens(&completion_breakdowns, "TEXT"),
+                image_tokens: modality_tokens(&completion_breakdowns, "IMAGE"),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GeminiPromptFeedback {
+    #[serde(rename = "blockReason")]
+    pub block_reason: Option<String>,
+    #[serde(rename = "blockReasonMessage")]
+    pub block_reason_message: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -238,6 +853,43 @@ pub struct GeminiResponse {
     pub candidates: Option<Vec<GeminiCandidate>>,
     #[serde(rename = "usageMetadata")]
     pub usage_metadata: Option<GeminiUsageMetadata>,
+    #[serde(rename = "promptFeedback")]
+    pub prompt_feedback: Option<GeminiPromptFeedback>,
+}
+
+impl GeminiResponse {
+    /// Describes why Gemini refused to process the prompt, if it did.
+    pub fn block_message(&self) -> Option<String> {
+        let feedback = self.prompt_feedback.as_ref()?;
+        let reason = feedback.block_reason.as_deref()?;
+        Some(match &feedback.block_reason_message {
+            Some(message) => format!("The prompt was blocked ({}): {}", reason, message),
+            None => format!("The prompt was blocked ({})", reason),
+        })
+    }
+}
+
+/// Maps a Gemini `finishReason` onto the values OpenAI clients branch on.
+pub fn map_finish_reason(reason: &str) -> &'static str {
+    match reason {
+        "STOP" => "stop",
+        "MAX_TOKENS" => "length",
+        "SAFETY"
+        | "RECITATION"
+        | "BLOCKLIST"
+        | "PROHIBITED_CONTENT"
+        | "SPII"
+        | "LANGUAGE"
+        | "IMAGE_SAFETY"
+        | "IMAGE_PROHIBITED_CONTENT"
+        | "IMAGE_RECITATION" => "content_filter",
+        "MALFORMED_FUNCTION_CALL" | "UNEXPECTED_TOOL_CALL" | "TOO_MANY_TOOL_CALLS" => {
+            log::warn!("Gemini stopped with {}", reason);
+            "stop"
+        }
+        // OTHER, FINISH_REASON_UNSPECIFIED, NO_IMAGE, IMAGE_OTHER, ...
+        _ => "stop",
+    }
 }
 
 pub fn transform_gemini_to_openai(gemini_res: &GeminiResponse, model: &str) -> OpenAIResponse {
@@ -246,16 +898,23 @@ pub fn transform_gemini_to_openai(gemini_res: &GeminiResponse, model: &str) -> O
     if let Some(candidates) = &gemini_res.candidates {
         for (i, candidate) in candidates.iter().enumerate() {
             let mut text = String::new();
+            let mut reasoning = String::new();
             let mut tool_calls = Vec::new();
             
             if let Some(content) = &candidate.content {
                 for part in &content.parts {
                     if let Some(t) = &part.text {
-                        text.push_str(t);
+                        if part.thought == Some(true) {
+                            reasoning.push_str(t);
+                        } else {
+                            text.push_str(t);
+                        }
                     }
                     if let Some(fc) = &part.function_call {
+                        let id = format!("call_{}", rand::random::<u64>());
+                        signatures::remember(&id, part.thought_signature.as_deref());
                         tool_calls.push(OpenAIToolCall {
-                            id: format!("call_{}", rand::random::<u32>()),
+                            id,
                             r#type: "function".to_string(),
                             function: OpenAIFunctionCall {
                                 name: fc.name.clone(),
@@ -266,24 +925,50 @@ pub fn transform_gemini_to_openai(gemini_res: &GeminiResponse, model: &str) -> O
                 }
             }
             
+            // Streamed chunks only carry a finishReason on the last one.
+            let finish_reason = candidate.finish_reason.as_deref().map(|reason| {
+                if !tool_calls.is_empty() && reason == "STOP" {
+                    "tool_calls".to_string()
+                } else {
+                    map_finish_reason(reason).to_string()
+                }
+            });
+
             choices.push(OpenAIChoice {
                 index: candidate.index.unwrap_or(i as u32),
                 message: OpenAIMessage {
                     role: "assistant".to_string(),
-                    content: if text.is_empty() { None } else { Some(text) },
+                    content: if text.is_empty() { None } else { Some(OpenAIContent::Text(text)) },
                     tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
                     tool_call_id: None,
+                    name: None,
+                    reasoning_content: if reasoning.is_empty() { None } else { Some(reasoning) },
+                    refusal: None,
                 },
-                finish_reason: candidate.finish_reason.clone().or(Some("stop".to_string())),
+                finish_reason,
             });
         }
     }
 
-    let usage = gemini_res.usage_metadata.as_ref().map(|u| OpenAIUsage {
-        prompt_tokens: u.prompt_token_count.unwrap_or(0),
-        completion_tokens: u.candidates_token_count.unwrap_or(0),
-        total_tokens: u.total_token_count.unwrap_or(0),
-    });
+    if choices.is_empty() {
+        if let Some(refusal) = gemini_res.block_message() {
+            choices.push(OpenAIChoice {
+                index: 0,
+                message: OpenAIMessage {
+                    role: "assistant".to_string(),
+                    content: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                    name: None,
+                    reasoning_content: None,
+                    refusal: Some(refusal),
+                },
+                finish_reason: Some("content_filter".to_string()),
+            });
+        }
+    }
+
+    let usage = gemini_res.usage_metadata.as_ref().map(|u| u.to_openai());
 
     OpenAIResponse {
         id: format!("chatcmpl-{}", rand::random::<u32>()),
@@ -294,3 +979,391 @@ pub fn transform_gemini_to_openai(gemini_res: &GeminiResponse, model: &str) -> O
         usage,
     }
 }
+
+/// Gemini has no switch to disable parallel function calling, so when a client
+/// sets `parallel_tool_calls: false` only the first call of each choice is kept.
+pub fn limit_to_single_tool_call(response: &mut OpenAIResponse) {
+    for choice in &mut response.choices {
+        if let Some(tool_calls) = &mut choice.message.tool_calls {
+            if tool_calls.len() > 1 {
+                log::warn!("Dropping {} extra tool calls because parallel_tool_calls is false", tool_calls.len() - 1);
+                tool_calls.truncate(1);
+            }
+        }
+    }
+}
+
+/// The sanitized schema the response must satisfy when the request asked for
+/// `json_schema` output with `strict: true`.
+pub fn strict_response_schema(request: &OpenAIRequest) -> Option<Value> {
+    match &request.response_format {
+        Some(OpenAIResponseFormat::JsonSchema { json_schema }) if json_schema.strict == Some(true) => {
+            json_schema.schema.as_ref()
+                .map(|s| schema::sanitize_schema(s, &format!("response_format '{}'", json_schema.name)))
+        }
+        _ => None,
+    }
+}
+
+/// Validates every text choice of a response against a strict schema.
+pub fn validate_response_schema(response: &OpenAIResponse, schema: &Value) -> std::result::Result<(), String> {
+    for choice in &response.choices {
+        if let Some(content) = &choice.message.content {
+            validate_choice_content(choice.index, &content.text(), schema)?;
+        }
+    }
+    Ok(())
+}
+
+/// Validates the full text of one choice against a strict schema.
+pub fn validate_choice_content(index: u32, content: &str, schema: &Value) -> std::result::Result<(), String> {
+    let value: Value = serde_json::from_str(content)
+        .map_err(|e| format!("choice {} is not valid JSON: {}", index, e))?;
+    schema::validate(&value, schema)
+        .map_err(|e| format!("choice {}: {}", index, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(content: &str) -> OpenAIResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gemini-2.5-pro",
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }],
+        })).unwrap()
+    }
+
+    /// Translates an OpenAI request given as JSON and returns the Gemini
+    /// request body as JSON.
+    fn translate_with(request: Value, options: &TransformOptions) -> Result<Value> {
+        let request: OpenAIRequest = serde_json::from_value(request).unwrap();
+        let (_, wrapped, _) = transform_openai_to_gemini(&request, "p", options)?;
+        Ok(serde_json::to_value(wrapped.request).unwrap())
+    }
+
+    fn translate(request: Value) -> Result<Value> {
+        translate_with(request, &TransformOptions::default())
+    }
+
+    /// The `param` of the 400 the request is rejected with.
+    fn rejected_param(result: Result<Value>) -> String {
+        let error = result.unwrap_err().downcast::<ApiError>().unwrap();
+        assert_eq!(error.status.as_u16(), 400);
+        error.param.unwrap()
+    }
+
+    fn chat(messages: Value) -> Value {
+        json!({ "model": "gemini-2.5-flash", "messages": messages })
+    }
+
+    fn tool_call(id: &str, name: &str) -> Value {
+        json!({ "id": id, "type": "function", "function": { "name": name, "arguments": "{}" } })
+    }
+
+    fn with_system_messages(system_messages: SystemMessagePolicy) -> TransformOptions {
+        TransformOptions { system_messages, ..Default::default() }
+    }
+
+    fn conversation_with_late_system_message() -> Value {
+        chat(json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "hi" },
+            { "role": "developer", "content": "Answer in French." },
+            { "role": "user", "content": "how are you?" },
+        ]))
+    }
+
+    #[test]
+    fn joins_leading_system_and_developer_messages() {
+        let gemini = translate(chat(json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "developer", "content": [{ "type": "text", "text": "Use metric units." }] },
+            { "role": "user", "content": "hi" },
+        ]))).unwrap();
+
+        assert_eq!(gemini["systemInstruction"]["parts"], json!([{ "text": "Be brief.\n\nUse metric units." }]));
+        assert_eq!(gemini["contents"], json!([{ "role": "user", "parts": [{ "text": "hi" }] }]));
+    }
+
+    #[test]
+    fn hoists_later_system_messages_by_default() {
+        let gemini = translate(conversation_with_late_system_message()).unwrap();
+
+        assert_eq!(gemini["systemInstruction"]["parts"], json!([{ "text": "Be brief.\n\nAnswer in French." }]));
+        let turns: Vec<_> = gemini["contents"].as_array().unwrap().iter().map(|c| c["parts"][0]["text"].clone()).collect();
+        assert_eq!(turns, [json!("hi"), json!("how are you?")]);
+    }
+
+    #[test]
+    fn inlines_later_system_messages_as_user_turns() {
+        let options = with_system_messages(SystemMessagePolicy::Inline);
+        let gemini = translate_with(conversation_with_late_system_message(), &options).unwrap();
+
+        assert_eq!(gemini["systemInstruction"]["parts"], json!([{ "text": "Be brief." }]));
+        assert_eq!(gemini["contents"], json!([
+            { "role": "user", "parts": [{ "text": "hi" }] },
+            { "role": "user", "parts": [{ "text": "Answer in French." }] },
+            { "role": "user", "parts": [{ "text": "how are you?" }] },
+        ]));
+    }
+
+    #[test]
+    fn rejects_later_system_messages() {
+        let options = with_system_messages(SystemMessagePolicy::Reject);
+        let result = translate_with(conversation_with_late_system_message(), &options);
+        assert_eq!(rejected_param(result), "messages[2]");
+
+        // Leading ones are still accepted.
+        let leading = chat(json!([{ "role": "system", "content": "Be brief." }, { "role": "user", "content": "hi" }]));
+        assert!(translate_with(leading, &options).is_ok());
+    }
+
+    #[test]
+    fn wraps_tool_results_that_are_not_json_objects() {
+        let response = |content: &str| tool_result_to_response(Some(&OpenAIContent::Text(content.to_string())));
+        assert_eq!(response(r#"{"temp":21}"#), json!({ "temp": 21 }));
+        assert_eq!(response("[1,2]"), json!({ "result": [1, 2] }));
+        assert_eq!(response("42"), json!({ "result": 42 }));
+        assert_eq!(response("\"sunny\""), json!({ "result": "sunny" }));
+        assert_eq!(response("sunny, 21C"), json!({ "result": "sunny, 21C" }));
+        assert_eq!(tool_result_to_response(None), json!({ "result": "" }));
+    }
+
+    #[test]
+    fn sends_parallel_tool_results_back_in_one_turn() {
+        let gemini = translate(chat(json!([
+            { "role": "user", "content": "weather and time?" },
+            { "role": "assistant", "content": null, "tool_calls": [tool_call("call_1", "weather"), tool_call("call_2", "time")] },
+            { "role": "tool", "tool_call_id": "call_2", "content": "12:00" },
+            { "role": "tool", "tool_call_id": "call_1", "content": r#"{"temp":21}"# },
+            { "role": "user", "content": "thanks" },
+        ]))).unwrap();
+
+        let contents = gemini["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 4);
+        assert_eq!(contents[2]["role"], "user");
+        assert_eq!(contents[2]["parts"], json!([
+            { "functionResponse": { "name": "time", "response": { "result": "12:00" } } },
+            { "functionResponse": { "name": "weather", "response": { "temp": 21 } } },
+        ]));
+        assert_eq!(contents[3]["parts"], json!([{ "text": "thanks" }]));
+    }
+
+    #[test]
+    fn rejects_tool_results_for_unknown_calls() {
+        let result = translate(chat(json!([
+            { "role": "user", "content": "weather?" },
+            { "role": "assistant", "tool_calls": [tool_call("call_1", "weather")] },
+            { "role": "tool", "tool_call_id": "call_9", "content": "sunny" },
+        ])));
+        assert_eq!(rejected_param(result), "messages[2].tool_call_id");
+    }
+
+    fn with_tools(tool_choice: Value) -> Value {
+        let mut request = chat(json!([{ "role": "user", "content": "weather?" }]));
+        request["tools"] = json!([
+            { "type": "function", "function": { "name": "weather", "parameters": { "type": "object" } } },
+            { "type": "function", "function": { "name": "time" } },
+        ]);
+        request["tool_choice"] = tool_choice;
+        request
+    }
+
+    #[test]
+    fn maps_tool_choice_modes() {
+        for (choice, mode) in [("auto", "AUTO"), ("none", "NONE"), ("required", "ANY")] {
+            let gemini = translate(with_tools(json!(choice))).unwrap();
+            assert_eq!(gemini["toolConfig"], json!({ "functionCallingConfig": { "mode": mode } }), "{}", choice);
+        }
+        assert_eq!(rejected_param(translate(with_tools(json!("sometimes")))), "tool_choice");
+    }
+
+    #[test]
+    fn forces_a_named_function() {
+        let gemini = translate(with_tools(json!({ "type": "function", "function": { "name": "time" } }))).unwrap();
+        assert_eq!(gemini["toolConfig"], json!({
+            "functionCallingConfig": { "mode": "ANY", "allowedFunctionNames": ["time"] },
+        }));
+
+        let unknown = with_tools(json!({ "type": "function", "function": { "name": "stocks" } }));
+        assert_eq!(rejected_param(translate(unknown)), "tool_choice");
+    }
+
+    #[test]
+    fn omits_the_tool_config_without_tools() {
+        let mut request = chat(json!([{ "role": "user", "content": "hi" }]));
+        request["tool_choice"] = json!("required");
+        assert!(translate(request).unwrap().get("toolConfig").is_none());
+    }
+
+    #[test]
+    fn keeps_the_first_tool_call_of_each_choice() {
+        let candidates = [0, 1].map(|index| json!({
+            "index": index,
+            "content": { "role": "model", "parts": [
+                { "functionCall": { "name": "weather", "args": {} } },
+                { "functionCall": { "name": "time", "args": {} } },
+            ] },
+            "finishReason": "STOP",
+        }));
+        let gemini: GeminiResponse = serde_json::from_value(json!({ "candidates": candidates })).unwrap();
+        let mut response = transform_gemini_to_openai(&gemini, "gemini-2.5-pro");
+        limit_to_single_tool_call(&mut response);
+
+        for choice in &response.choices {
+            let names: Vec<_> = choice.message.tool_calls.iter().flatten().map(|c| c.function.name.as_str()).collect();
+            assert_eq!(names, ["weather"]);
+            assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        }
+    }
+
+    fn with_sampling(params: Value) -> Value {
+        let mut request = chat(json!([{ "role": "user", "content": "hi" }]));
+        request.as_object_mut().unwrap().extend(params.as_object().unwrap().clone());
+        request
+    }
+
+    #[test]
+    fn rejects_out_of_range_sampling_parameters() {
+        let cases = [
+            (json!({ "temperature": 2.5 }), "temperature"),
+            (json!({ "temperature": -0.1 }), "temperature"),
+            (json!({ "top_p": 1.5 }), "top_p"),
+            (json!({ "presence_penalty": -3.0 }), "presence_penalty"),
+            (json!({ "frequency_penalty": 2.1 }), "frequency_penalty"),
+            (json!({ "top_k": 0 }), "top_k"),
+            (json!({ "max_tokens": 0 }), "max_tokens"),
+            (json!({ "max_completion_tokens": 0 }), "max_completion_tokens"),
+            (json!({ "n": 0 }), "n"),
+            (json!({ "n": 9 }), "n"),
+            (json!({ "seed": 1u64 << 31 }), "seed"),
+            (json!({ "seed": -(1i64 << 31) - 1 }), "seed"),
+            (json!({ "reasoning_effort": "maximal" }), "reasoning_effort"),
+            (json!({ "stop": ["a", "b", "c", "d", "e", "f"] }), "stop"),
+        ];
+        for (params, param) in cases {
+            assert_eq!(rejected_param(translate(with_sampling(params.clone()))), param, "{}", params);
+        }
+    }
+
+    #[test]
+    fn passes_sampling_parameters_through() {
+        let gemini = translate(with_sampling(json!({
+            "temperature": 2.0, "top_p": 0.5, "top_k": 40, "n": 8, "seed": -7,
+            "presence_penalty": -2.0, "frequency_penalty": 2.0,
+            "stop": ["a", "b", "c", "d", "e"], "max_tokens": 100,
+        }))).unwrap();
+        assert_eq!(gemini["generationConfig"], json!({
+            "temperature": 2.0, "topP": 0.5, "topK": 40, "candidateCount": 8, "seed": -7,
+            "presencePenalty": -2.0, "frequencyPenalty": 2.0,
+            "stopSequences": ["a", "b", "c", "d", "e"], "maxOutputTokens": 100,
+        }));
+    }
+
+    #[test]
+    fn prefers_max_completion_tokens_over_max_tokens() {
+        let gemini = translate(with_sampling(json!({ "max_tokens": 100, "max_completion_tokens": 200 }))).unwrap();
+        assert_eq!(gemini["generationConfig"]["maxOutputTokens"], 200);
+    }
+
+    #[test]
+    fn maps_finish_reasons() {
+        let cases = [
+            ("STOP", "stop"),
+            ("MAX_TOKENS", "length"),
+            ("SAFETY", "content_filter"),
+            ("RECITATION", "content_filter"),
+            ("BLOCKLIST", "content_filter"),
+            ("PROHIBITED_CONTENT", "content_filter"),
+            ("SPII", "content_filter"),
+            ("IMAGE_SAFETY", "content_filter"),
+            ("MALFORMED_FUNCTION_CALL", "stop"),
+            ("OTHER", "stop"),
+            ("FINISH_REASON_UNSPECIFIED", "stop"),
+        ];
+        for (reason, expected) in cases {
+            assert_eq!(map_finish_reason(reason), expected, "{}", reason);
+        }
+    }
+
+    #[test]
+    fn reports_tool_calls_when_gemini_stops_after_a_function_call() {
+        let gemini: GeminiResponse = serde_json::from_value(json!({ "candidates": [
+            { "index": 0, "content": { "role": "model", "parts": [{ "functionCall": { "name": "weather", "args": {} } }] }, "finishReason": "STOP" },
+            { "index": 1, "content": { "role": "model", "parts": [{ "functionCall": { "name": "weather", "args": {} } }] }, "finishReason": "MAX_TOKENS" },
+            { "index": 2, "content": { "role": "model", "parts": [{ "text": "sunny" }] }, "finishReason": "STOP" },
+        ] })).unwrap();
+        let reasons: Vec<_> = transform_gemini_to_openai(&gemini, "gemini-2.5-pro").choices.into_iter()
+            .map(|c| c.finish_reason.unwrap())
+            .collect();
+        assert_eq!(reasons, ["tool_calls", "length", "stop"]);
+    }
+
+    #[test]
+    fn turns_a_blocked_prompt_into_a_refusal() {
+        let gemini: GeminiResponse = serde_json::from_value(json!({
+            "promptFeedback": { "blockReason": "SAFETY", "blockReasonMessage": "unsafe" },
+        })).unwrap();
+        let response = transform_gemini_to_openai(&gemini, "gemini-2.5-pro");
+
+        assert_eq!(response.choices.len(), 1);
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.refusal.as_deref(), Some("The prompt was blocked (SAFETY): unsafe"));
+        assert!(choice.message.content.is_none());
+        assert_eq!(choice.finish_reason.as_deref(), Some("content_filter"));
+    }
+
+    #[test]
+    fn counts_thoughts_and_tool_use_prompts_in_openai_usage() {
+        let metadata: GeminiUsageMetadata = serde_json::from_value(json!({
+            "promptTokenCount": 100,
+            "toolUsePromptTokenCount": 20,
+            "candidatesTokenCount": 30,
+            "thoughtsTokenCount": 50,
+            "cachedContentTokenCount": 40,
+            "totalTokenCount": 200,
+            "promptTokensDetails": [{ "modality": "TEXT", "tokenCount": 90 }, { "modality": "IMAGE", "tokenCount": 10 }],
+            "toolUsePromptTokensDetails": [{ "modality": "TEXT", "tokenCount": 20 }],
+            "candidatesTokensDetails": [{ "modality": "TEXT", "tokenCount": 30 }],
+        })).unwrap();
+        let usage = json!(metadata.to_openai());
+
+        assert_eq!(usage, json!({
+            "prompt_tokens": 120,
+            "completion_tokens": 80,
+            "total_tokens": 200,
+            "prompt_tokens_details": { "cached_tokens": 40, "text_tokens": 110, "image_tokens": 10 },
+            "completion_tokens_details": { "reasoning_tokens": 50, "text_tokens": 30 },
+        }));
+    }
+
+    #[test]
+    fn fills_missing_usage_counts_with_zero() {
+        let metadata: GeminiUsageMetadata = serde_json::from_value(json!({ "promptTokenCount": 7 })).unwrap();
+        let usage = json!(metadata.to_openai());
+
+        assert_eq!(usage, json!({
+            "prompt_tokens": 7,
+            "completion_tokens": 0,
+            "total_tokens": 7,
+            "prompt_tokens_details": { "cached_tokens": 0 },
+            "completion_tokens_details": { "reasoning_tokens": 0 },
+        }));
+    }
+
+    #[test]
+    fn validates_every_choice_against_the_strict_schema() {
+        let schema = json!({ "type": "object", "properties": { "n": { "type": "integer" } }, "required": ["n"] });
+        assert!(validate_response_schema(&response(r#"{"n":1}"#), &schema).is_ok());
+        assert_eq!(
+            validate_response_schema(&response(r#"{"m":1}"#), &schema).unwrap_err(),
+            "choice 0: $ is missing required property 'n'",
+        );
+        assert!(validate_response_schema(&response("not json"), &schema).unwrap_err().starts_with("choice 0 is not valid JSON"));
+    }
+}
@@ -0,0 +1,534 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+/// Keywords Gemini's OpenAPI-subset `Schema` accepts. Everything else is
+/// dropped.
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "type",
+    "format",
+    "title",
+    "description",
+    "nullable",
+    "enum",
+    "default",
+    "example",
+    "properties",
+    "required",
+    "propertyOrdering",
+    "minProperties",
+    "maxProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "anyOf",
+];
+
+struct Sanitizer<'a> {
+    root: &'a Value,
+    removed: BTreeSet<String>,
+    /// `$ref`s being expanded; seeing one again means the schema is
+    /// recursive.
+    expanding: Vec<String>,
+}
+
+/// Rewrites an OpenAI/JSON Schema document into the subset Gemini accepts:
+/// `$ref`s are inlined, nullable unions collapse into `nullable`, `const`
+/// becomes a single-value `enum` and unsupported keywords are dropped.
+/// `context` names the schema's owner in the warning about removed keywords.
+pub fn sanitize_schema(schema: &Value, context: &str) -> Value {
+    let mut sanitizer = Sanitizer {
+        root: schema,
+        removed: BTreeSet::new(),
+        expanding: Vec::new(),
+    };
+    let result = sanitizer.sanitize(schema);
+
+    if !sanitizer.removed.is_empty() {
+        log::warn!(
+            "Removed unsupported JSON Schema keywords from {}: {}",
+            context,
+            sanitizer.removed.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    result
+}
+
+impl Sanitizer<'_> {
+    fn sanitize(&mut self, schema: &Value) -> Value {
+        let Some(obj) = schema.as_object() else {
+            // `true`/`false` schemas carry no type information.
+            return Value::Object(Map::new());
+        };
+
+        let mut obj = obj.clone();
+
+        if let Some(reference) = obj.remove("$ref") {
+            let reference = reference.as_str().unwrap_or_default().to_string();
+            // Recursive schemas cannot be inlined; the cycle becomes a plain object.
+            let resolved = self.resolve_ref(&reference).filter(|_| !self.expanding.contains(&reference));
+            match resolved {
+                Some(target) => {
+                    // Sibling keywords (e.g. a description) override the target.
+                    let mut merged = target.as_object().cloned().unwrap_or_default();
+                    merged.extend(obj);
+                    self.expanding.push(reference);
+                    let result = self.sanitize(&Value::Object(merged));
+                    self.expanding.pop();
+                    return result;
+                }
+                None => {
+                    self.removed.insert("$ref".to_string());
+                    obj.insert("type".to_string(), Value::String("object".to_string()));
+                    return self.sanitize(&Value::Object(obj));
+                }
+            }
+        }
+
+        if let Some(Value::Array(all_of)) = obj.remove("allOf") {
+            for sub in all_of {
+                let sub = self.sanitize(&sub);
+                merge_schema(&mut obj, sub);
+            }
+        }
+
+        let mut nullable = obj.remove("nullable").and_then(|v| v.as_bool()).unwrap_or(false);
+
+        // `"type": ["string", "null"]`
+        if let Some(Value::Array(types)) = obj.get("type").cloned() {
+            let (nulls, others): (Vec<_>, Vec<_>) = types.into_iter().partition(|t| t == "null");
+            nullable |= !nulls.is_empty();
+            match others.len() {
+                0 => {
+                    obj.remove("type");
+                }
+                1 => {
+                    obj.insert("type".to_string(), others[0].clone());
+                }
+                _ => {
+                    obj.remove("type");
+                    let variants = others.into_iter().map(|t| serde_json::json!({ "type": t })).collect();
+                    obj.insert("anyOf".to_string(), Value::Array(variants));
+                }
+            }
+        }
+
+        // `anyOf`/`oneOf` with a `{"type": "null"}` branch.
+        for key in ["anyOf", "oneOf"] {
+            let Some(Value::Array(variants)) = obj.remove(key) else {
+                continue;
+            };
+            let mut kept = Vec::new();
+            for variant in variants {
+                if variant.get("type").and_then(|t| t.as_str()) == Some("null") {
+                    nullable = true;
+                } else {
+                    kept.push(self.sanitize(&variant));
+                }
+            }
+            match kept.len() {
+                0 => {}
+                1 => merge_schema(&mut obj, kept.remove(0)),
+                _ => {
+                    obj.insert("anyOf".to_string(), Value::Array(kept));
+                }
+            }
+        }
+
+        if let Some(value) = obj.remove("const") {
+            if let Value::String(_) = value {
+                obj.entry("type").or_insert_with(|| Value::String("string".to_string()));
+                obj.insert("enum".to_string(), Value::Array(vec![value]));
+            } else {
+                self.removed.insert("const".to_string());
+            }
+        }
+
+        if let Some(Value::Object(properties)) = obj.remove("properties") {
+            let properties: Map<String, Value> = properties
+                .iter()
+                .map(|(name, prop)| (name.clone(), self.sanitize(prop)))
+                .collect();
+            // Gemini rejects `required` entries that are not declared properties.
+            if let Some(Value::Array(required)) = obj.get_mut("required") {
+                required.retain(|r| r.as_str().is_some_and(|r| properties.contains_key(r)));
+            }
+            obj.insert("properties".to_string(), Value::Object(properties));
+        }
+
+        if let Some(items) = obj.remove("items") {
+            let items = match items {
+                // Tuple-style `items` arrays are not supported; use the first entry.
+                Value::Array(mut tuple) if !tuple.is_empty() => {
+                    self.removed.insert("items[]".to_string());
+                    self.sanitize(&tuple.remove(0))
+                }
+                other => self.sanitize(&other),
+            };
+            obj.insert("items".to_string(), items);
+        }
+
+        // Definitions have been inlined wherever they were referenced.
+        obj.remove("$defs");
+        obj.remove("definitions");
+
+        let unsupported: Vec<String> = obj
+            .keys()
+            .filter(|k| !SUPPORTED_KEYWORDS.contains(&k.as_str()))
+            .cloned()
+            .collect();
+        for key in unsupported {
+            obj.remove(&key);
+            self.removed.insert(key);
+        }
+
+        if nullable {
+            obj.insert("nullable".to_string(), Value::Bool(true));
+        }
+
+        Value::Object(obj)
+    }
+
+    /// Resolves a local JSON pointer such as `#/$defs/Item`.
+    fn resolve_ref(&self, reference: &str) -> Option<&Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+}
+
+/// Merges `other` into `target`, combining `properties` and `required`
+/// instead of overwriting them.
+fn merge_schema(target: &mut Map<String, Value>, other: Value) {
+    let Value::Object(other) = other else {
+        return;
+    };
+    for (key, value) in other {
+        match (target.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(new)) if key == "properties" => {
+                existing.extend(new);
+            }
+            (Some(Value::Array(existing)), Value::Array(new)) if key == "required" => {
+                for r in new {
+                    if !existing.contains(&r) {
+                        existing.push(r);
+                    }
+                }
+            }
+            // Keywords already on the outer schema take precedence.
+            (Some(_), _) => {}
+            (None, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Checks `value` against a schema produced by [`sanitize_schema`]. Returns a
+/// description of the first mismatch.
+pub fn validate(value: &Value, schema: &Value) -> Result<(), String> {
+    validate_at(value, schema, "$")
+}
+
+fn validate_at(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        return Ok(());
+    };
+
+    if value.is_null() {
+        let nullable = schema.get("nullable").and_then(|n| n.as_bool()).unwrap_or(false);
+        if nullable || !schema.contains_key("type") {
+            return Ok(());
+        }
+        return Err(format!("{} must not be null", path));
+    }
+
+    if let Some(Value::Array(variants)) = schema.get("anyOf") {
+        if !variants.iter().any(|v| validate_at(value, v, path).is_ok()) {
+            return Err(format!("{} does not match any allowed schema", path));
+        }
+    }
+
+    if let Some(expected) = schema.get("type").and_then(|t| t.as_str()) {
+        let matches = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("{} must be of type {}", path, expected));
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(format!("{} must be one of {}", path, Value::Array(allowed.clone())));
+        }
+    }
+
+    match value {
+        Value::Object(obj) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(|r| r.as_str()) {
+                    if !obj.contains_key(name) {
+                        return Err(format!("{} is missing required property '{}'", path, name));
+                    }
+                }
+            }
+            if let Some(Value::Object(properties)) = schema.get("properties") {
+                for (name, prop) in obj {
+                    if let Some(prop_schema) = properties.get(name) {
+                        validate_at(prop, prop_schema, &format!("{}.{}", path, name))?;
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = schema.get("minItems").and_then(as_u64) {
+                if len < min {
+                    return Err(format!("{} must have at least {} items", path, min));
+                }
+            }
+            if let Some(max) = schema.get("maxItems").and_then(as_u64) {
+                if len > max {
+                    return Err(format!("{} must have at most {} items", path, max));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item, item_schema, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(as_u64) {
+                if len < min {
+                    return Err(format!("{} must be at least {} characters", path, min));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(as_u64) {
+                if len > max {
+                    return Err(format!("{} must be at most {} characters", path, max));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(0.0);
+            if let Some(min) = schema.get("minimum").and_then(|m| m.as_f64()) {
+                if n < min {
+                    return Err(format!("{} must be >= {}", path, min));
+                }
+            }
+            if let Some(max) = schema.get("maximum").and_then(|m| m.as_f64()) {
+                if n > max {
+                    return Err(format!("{} must be <= {}", path, max));
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn as_u64(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sanitize(schema: Value) -> Value {
+        sanitize_schema(&schema, "test")
+    }
+
+    #[test]
+    fn inlines_pydantic_defs_and_optional_fields() {
+        let address = json!({
+            "properties": {
+                "street": { "title": "Street", "type": "string" },
+                "zip": { "anyOf": [{ "type": "string" }, { "type": "null" }], "default": null, "title": "Zip" },
+            },
+            "required": ["street"],
+            "title": "Address",
+            "type": "object",
+        });
+        let schema = json!({
+            "$defs": { "Address": address },
+            "properties": {
+                "name": { "title": "Name", "type": "string" },
+                "address": { "$ref": "#/$defs/Address" },
+                "previous": { "anyOf": [{ "$ref": "#/$defs/Address" }, { "type": "null" }], "default": null },
+            },
+            "required": ["name", "address"],
+            "title": "Person",
+            "type": "object",
+            "additionalProperties": false,
+        });
+
+        let expected_address = json!({
+            "properties": {
+                "street": { "title": "Street", "type": "string" },
+                "zip": { "type": "string", "default": null, "title": "Zip", "nullable": true },
+            },
+            "required": ["street"],
+            "title": "Address",
+            "type": "object",
+        });
+        let mut expected_previous = expected_address.clone();
+        expected_previous["default"] = Value::Null;
+        expected_previous["nullable"] = Value::Bool(true);
+        assert_eq!(sanitize(schema), json!({
+            "properties": {
+                "name": { "title": "Name", "type": "string" },
+                "address": expected_address,
+                "previous": expected_previous,
+            },
+            "required": ["name", "address"],
+            "title": "Person",
+            "type": "object",
+        }));
+    }
+
+    #[test]
+    fn collapses_type_arrays() {
+        assert_eq!(sanitize(json!({ "type": ["string", "null"] })), json!({ "type": "string", "nullable": true }));
+        assert_eq!(
+            sanitize(json!({ "type": ["string", "integer"] })),
+            json!({ "anyOf": [{ "type": "string" }, { "type": "integer" }] }),
+        );
+    }
+
+    #[test]
+    fn merges_all_of() {
+        let schema = json!({
+            "type": "object",
+            "allOf": [
+                { "properties": { "a": { "type": "string" } }, "required": ["a"] },
+                { "properties": { "b": { "type": "integer" } }, "required": ["b"] },
+            ],
+        });
+        assert_eq!(sanitize(schema), json!({
+            "type": "object",
+            "properties": { "a": { "type": "string" }, "b": { "type": "integer" } },
+            "required": ["a", "b"],
+        }));
+    }
+
+    #[test]
+    fn turns_string_const_into_enum() {
+        assert_eq!(sanitize(json!({ "const": "x" })), json!({ "type": "string", "enum": ["x"] }));
+        assert_eq!(sanitize(json!({ "const": 3 })), json!({}));
+    }
+
+    #[test]
+    fn prunes_required_entries_without_properties() {
+        let schema = json!({ "type": "object", "properties": { "a": { "type": "string" } }, "required": ["a", "ghost"] });
+        assert_eq!(sanitize(schema)["required"], json!(["a"]));
+    }
+
+    #[test]
+    fn uses_first_entry_of_tuple_items() {
+        let schema = json!({ "type": "array", "items": [{ "type": "string" }, { "type": "integer" }] });
+        assert_eq!(sanitize(schema), json!({ "type": "array", "items": { "type": "string" } }));
+    }
+
+    #[test]
+    fn inlines_deeply_nested_refs() {
+        let mut defs = Map::new();
+        for level in 0..10 {
+            defs.insert(format!("L{}", level), json!({
+                "type": "object",
+                "properties": { "child": { "$ref": format!("#/$defs/L{}", level + 1) } },
+            }));
+        }
+        defs.insert("L10".to_string(), json!({ "type": "string" }));
+        let schema = json!({ "$defs": defs, "$ref": "#/$defs/L0" });
+
+        let mut node = sanitize(schema);
+        for _ in 0..10 {
+            node = node["properties"]["child"].take();
+        }
+        assert_eq!(node, json!({ "type": "string" }));
+    }
+
+    #[test]
+    fn cuts_recursive_refs() {
+        let schema = json!({
+            "$defs": {
+                "Node": {
+                    "type": "object",
+                    "properties": { "children": { "type": "array", "items": { "$ref": "#/$defs/Node" } } },
+                },
+            },
+            "$ref": "#/$defs/Node",
+        });
+        assert_eq!(sanitize(schema), json!({
+            "type": "object",
+            "properties": { "children": { "type": "array", "items": { "type": "object" } } },
+        }));
+    }
+
+    #[test]
+    fn validates_nullable_values() {
+        let schema = sanitize(json!({ "type": ["string", "null"] }));
+        assert!(validate(&json!(null), &schema).is_ok());
+        assert!(validate(&json!("x"), &schema).is_ok());
+        assert_eq!(validate(&json!(null), &json!({ "type": "string" })).unwrap_err(), "$ must not be null");
+    }
+
+    #[test]
+    fn validates_any_of() {
+        let schema = sanitize(json!({ "anyOf": [{ "type": "string" }, { "type": "integer" }] }));
+        assert!(validate(&json!("x"), &schema).is_ok());
+        assert!(validate(&json!(3), &schema).is_ok());
+        assert_eq!(validate(&json!(true), &schema).unwrap_err(), "$ does not match any allowed schema");
+    }
+
+    #[test]
+    fn validates_enum() {
+        let schema = json!({ "type": "string", "enum": ["red", "green"] });
+        assert!(validate(&json!("red"), &schema).is_ok());
+        assert_eq!(validate(&json!("blue"), &schema).unwrap_err(), r#"$ must be one of ["red","green"]"#);
+    }
+
+    #[test]
+    fn validates_required_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+            "required": ["name"],
+        });
+        assert!(validate(&json!({ "name": "a" }), &schema).is_ok());
+        assert_eq!(validate(&json!({ "age": 3 }), &schema).unwrap_err(), "$ is missing required property 'name'");
+        assert_eq!(validate(&json!({ "name": "a", "age": 1.5 }), &schema).unwrap_err(), "$.age must be of type integer");
+    }
+
+    #[test]
+    fn validates_nested_arrays() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "rows": {
+                    "type": "array",
+                    "items": { "type": "array", "items": { "type": "integer" }, "minItems": 1 },
+                },
+            },
+        });
+        assert!(validate(&json!({ "rows": [[1, 2], [3]] }), &schema).is_ok());
+        assert_eq!(validate(&json!({ "rows": [[1], ["2"]] }), &schema).unwrap_err(), "$.rows[1][0] must be of type integer");
+        assert_eq!(validate(&json!({ "rows": [[1], []] }), &schema).unwrap_err(), "$.rows[1] must have at least 1 items");
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+
+/// Sentinel Gemini accepts in place of a thought signature it never issued.
+pub const SKIP_THOUGHT_SIGNATURE: &str = "skip_thought_signature_validator";
+
+/// Number of tool calls whose signatures are remembered before the oldest are
+/// evicted.
+const CAPACITY: usize = 10_000;
+
+/// Thought signatures Gemini attached to the function calls it returned,
+/// keyed by the tool call id we handed to the client. A call that came back
+/// without a signature (e.g. the second of two parallel calls) is recorded as
+/// `None` so it is replayed without one.
+struct SignatureCache {
+    entries: HashMap<String, Option<String>>,
+    order: VecDeque<String>,
+}
+
+impl SignatureCache {
+    fn new() -> Self {
+        SignatureCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, tool_call_id: &str, signature: Option<&str>) {
+        if self.entries.insert(tool_call_id.to_string(), signature.map(|s| s.to_string())).is_none() {
+            self.order.push_back(tool_call_id.to_string());
+        }
+        while self.order.len() > CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn get(&self, tool_call_id: &str) -> Option<String> {
+        match self.entries.get(tool_call_id) {
+            Some(signature) => signature.clone(),
+            None => Some(SKIP_THOUGHT_SIGNATURE.to_string()),
+        }
+    }
+}
+
+static CACHE: LazyLock<Mutex<SignatureCache>> = LazyLock::new(|| Mutex::new(SignatureCache::new()));
+
+pub fn remember(tool_call_id: &str, signature: Option<&str>) {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner()).insert(tool_call_id, signature);
+}
+
+/// The signature to send back with a tool call from the conversation history.
+/// Falls back to the skip sentinel for calls this proxy has not seen.
+pub fn signature_for(tool_call_id: &str) -> Option<String> {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner()).get(tool_call_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::{transform_gemini_to_openai, transform_openai_to_gemini, GeminiResponse, TransformOptions};
+    use serde_json::json;
+
+    #[test]
+    fn skips_validation_for_unknown_calls() {
+        let cache = SignatureCache::new();
+        assert_eq!(cache.get("call_unknown").as_deref(), Some(SKIP_THOUGHT_SIGNATURE));
+    }
+
+    #[test]
+    fn replays_unsigned_calls_without_a_signature() {
+        let mut cache = SignatureCache::new();
+        cache.insert("call_1", Some("sig"));
+        cache.insert("call_2", None);
+        assert_eq!(cache.get("call_1").as_deref(), Some("sig"));
+        assert_eq!(cache.get("call_2"), None);
+    }
+
+    #[test]
+    fn evicts_the_oldest_calls_beyond_capacity() {
+        let mut cache = SignatureCache::new();
+        for i in 0..CAPACITY + 2 {
+            cache.insert(&format!("call_{}", i), Some("sig"));
+        }
+        // Re-recording a known call does not grow the cache.
+        cache.insert("call_5", Some("new"));
+
+        assert_eq!(cache.entries.len(), CAPACITY);
+        assert_eq!(cache.order.len(), CAPACITY);
+        assert_eq!(cache.get("call_0").as_deref(), Some(SKIP_THOUGHT_SIGNATURE));
+        assert_eq!(cache.get("call_1").as_deref(), Some(SKIP_THOUGHT_SIGNATURE));
+        assert_eq!(cache.get("call_2").as_deref(), Some("sig"));
+        assert_eq!(cache.get("call_5").as_deref(), Some("new"));
+        assert_eq!(cache.get(&format!("call_{}", CAPACITY + 1)).as_deref(), Some("sig"));
+    }
+
+    #[test]
+    fn reattaches_signatures_to_replayed_tool_calls() {
+        let gemini: GeminiResponse = serde_json::from_value(json!({
+            "candidates": [{ "content": { "role": "model", "parts": [
+                { "functionCall": { "name": "weather", "args": { "city": "Oslo" } }, "thoughtSignature": "c2lnbmF0dXJl" },
+                { "functionCall": { "name": "time", "args": {} } },
+            ] }, "finishReason": "STOP" }],
+        })).unwrap();
+        let response = transform_gemini_to_openai(&gemini, "gemini-2.5-pro");
+        let assistant = serde_json::to_value(&response.choices[0].message).unwrap();
+
+        let request = serde_json::from_value(json!({
+            "model": "gemini-2.5-pro",
+            "messages": [{ "role": "user", "content": "weather and time in Oslo?" }, assistant],
+        })).unwrap();
+        let (_, wrapped, _) = transform_openai_to_gemini(&request, "p", &TransformOptions::default()).unwrap();
+        let parts = &wrapped.request.contents[1].parts;
+        assert_eq!(parts[0].function_call.as_ref().unwrap().name, "weather");
+        assert_eq!(parts[0].thought_signature.as_deref(), Some("c2lnbmF0dXJl"));
+        assert_eq!(parts[1].function_call.as_ref().unwrap().name, "time");
+        assert_eq!(parts[1].thought_signature, None);
+    }
+}
@@ -0,0 +1,303 @@
+use serde_json::{json, Map, Value};
+use std::collections::btree_map::{BTreeMap, Entry};
+use super::{
+    GeminiResponse, OpenAIRequest, OpenAIUsage, strict_response_schema, transform_gemini_to_openai,
+    validate_choice_content,
+};
+use crate::error::ApiError;
+
+/// Turns the sequence of upstream Gemini stream responses into OpenAI
+/// `chat.completion.chunk` objects. All chunks share one id and timestamp,
+/// each choice opens with a role delta, tool calls are numbered per choice,
+/// and finish reasons are held back until the upstream stream ends.
+pub struct StreamTransformer {
+    id: String,
+    created: u64,
+    model: String,
+    include_usage: bool,
+    single_tool_call: bool,
+    /// Finish reason per choice index, for every choice seen so far.
+    finish_reasons: BTreeMap<u32, Option<String>>,
+    /// Number of tool calls emitted per choice index.
+    tool_call_counts: BTreeMap<u32, usize>,
+    usage: Option<OpenAIUsage>,
+    /// Set for strict `json_schema` requests, whose content is collected per
+    /// choice index and validated once the stream ends.
+    strict_schema: Option<Value>,
+    contents: BTreeMap<u32, String>,
+}
+
+impl StreamTransformer {
+    pub fn new(request: &OpenAIRequest) -> Self {
+        StreamTransformer {
+            id: format!("chatcmpl-{}", rand::random::<u64>()),
+            created: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+            model: request.model.clone(),
+            include_usage: request.stream_options.as_ref()
+                .and_then(|o| o.include_usage)
+                .unwrap_or(false),
+            single_tool_call: request.parallel_tool_calls == Some(false),
+            finish_reasons: BTreeMap::new(),
+            tool_call_counts: BTreeMap::new(),
+            usage: None,
+            strict_schema: strict_response_schema(request),
+            contents: BTreeMap::new(),
+        }
+    }
+
+    /// Reports a different model in the chunks, e.g. after a fallback.
+    pub fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    fn chunk(&self, choices: Vec<Value>) -> Value {
+        let mut chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        });
+        if self.include_usage {
+            chunk["usage"] = Value::Null;
+        }
+        chunk
+    }
+
+    /// Converts one upstream response into zero or more chunks.
+    pub fn push(&mut self, gemini_res: &GeminiResponse) -> Vec<Value> {
+        if let Some(usage) = &gemini_res.usage_metadata {
+            self.usage = Some(usage.to_openai());
+        }
+
+        let openai_res = transform_gemini_to_openai(gemini_res, &self.model);
+        let mut chunks = Vec::new();
+        for choice in openai_res.choices {
+            let mut delta = Map::new();
+            if let Entry::Vacant(entry) = self.finish_reasons.entry(choice.index) {
+                entry.insert(None);
+                chunks.push(self.chunk(vec![json!({
+                    "index": choice.index,
+                    "delta": { "role": "assistant", "content": "" },
+                    "finish_reason": null,
+                })]));
+            }
+            if let Some(reason) = choice.finish_reason {
+                self.finish_reasons.insert(choice.index, Some(reason));
+            }
+
+            if let Some(reasoning) = choice.message.reasoning_content {
+                delta.insert("reasoning_content".to_string(), json!(reasoning));
+            }
+            if let Some(content) = choice.message.content {
+                let text = content.text();
+                if self.strict_schema.is_some() {
+                    self.contents.entry(choice.index).or_default().push_str(&text);
+                }
+                delta.insert("content".to_string(), json!(text));
+            }
+            if let Some(refusal) = choice.message.refusal {
+                delta.insert("refusal".to_string(), json!(refusal));
+            }
+            if !delta.is_empty() {
+                chunks.push(self.chunk(vec![json!({
+                    "index": choice.index,
+                    "delta": delta,
+                    "finish_reason": null,
+                })]));
+            }
+
+            // Gemini sends each function call whole: announce it with its id
+            // and name, then send the arguments as a separate fragment.
+            for tool_call in choice.message.tool_calls.unwrap_or_default() {
+                let count = self.tool_call_counts.entry(choice.index).or_insert(0);
+                if self.single_tool_call && *count > 0 {
+                    log::warn!("Dropping streamed tool call {} because parallel_tool_calls is false", tool_call.function.name);
+                    continue;
+                }
+                let index = *count;
+                *count += 1;
+
+                chunks.push(self.chunk(vec![json!({
+                    "index": choice.index,
+                    "delta": { "tool_calls": [{
+                        "index": index,
+                        "id": tool_call.id,
+                        "type": "function",
+                        "function": { "name": tool_call.function.name, "arguments": "" },
+                    }] },
+                    "finish_reason": null,
+                })]));
+                chunks.push(self.chunk(vec![json!({
+                    "index": choice.index,
+                    "delta": { "tool_calls": [{
+                        "index": index,
+                        "function": { "arguments": tool_call.function.arguments },
+                    }] },
+                    "finish_reason": null,
+                })]));
+            }
+        }
+        chunks
+    }
+
+    /// Chunks to send once the upstream stream has ended: one carrying each
+    /// choice's finish reason, then the usage chunk if it was requested.
+    /// Fails instead if the content of a strict `json_schema` request does
+    /// not match the schema; the content has already been sent by then.
+    pub fn finish(&mut self) -> Result<Vec<Value>, ApiError> {
+        if let Some(schema) = &self.strict_schema {
+            for (index, content) in &self.contents {
+                validate_choice_content(*index, content, schema).map_err(ApiError::response_schema_mismatch)?;
+            }
+        }
+
+        if self.finish_reasons.is_empty() {
+            // Upstream ended without producing anything; still close choice 0.
+            self.finish_reasons.insert(0, None);
+        }
+
+        let choices = self.finish_reasons.iter().map(|(index, reason)| {
+            let reason = reason.as_deref().unwrap_or("stop");
+            // The calls may have arrived in an earlier chunk than the STOP.
+            let has_tool_calls = self.tool_call_counts.get(index).is_some_and(|c| *c > 0);
+            let reason = if has_tool_calls && reason == "stop" { "tool_calls" } else { reason };
+            json!({
+                "index": index,
+                "delta": {},
+                "finish_reason": reason,
+            })
+        }).collect();
+        let mut chunks = vec![self.chunk(choices)];
+
+        if self.include_usage {
+            let mut usage_chunk = self.chunk(Vec::new());
+            usage_chunk["usage"] = json!(self.usage.clone().unwrap_or_default());
+            chunks.push(usage_chunk);
+        }
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transformer(request: Value) -> StreamTransformer {
+        let mut request = request;
+        request["model"] = json!("gemini-2.5-pro");
+        request["messages"] = json!([]);
+        StreamTransformer::new(&serde_json::from_value(request).unwrap())
+    }
+
+    fn gemini(parts: Value, finish_reason: Option<&str>) -> GeminiResponse {
+        serde_json::from_value(json!({
+            "candidates": [{ "content": { "role": "model", "parts": parts }, "finishReason": finish_reason, "index": 0 }],
+        })).unwrap()
+    }
+
+    fn call(name: &str) -> Value {
+        json!({ "functionCall": { "name": name, "args": { "q": name } } })
+    }
+
+    /// `(index, id)` of every tool call delta that opens a call.
+    fn opened_tool_calls(chunks: &[Value]) -> Vec<(u64, String)> {
+        chunks.iter()
+            .flat_map(|c| c["choices"].as_array().cloned().unwrap_or_default())
+            .flat_map(|choice| choice["delta"]["tool_calls"].as_array().cloned().unwrap_or_default())
+            .filter(|call| call.get("id").is_some())
+            .map(|call| (call["index"].as_u64().unwrap(), call["function"]["name"].as_str().unwrap().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn shares_id_and_holds_finish_reason_until_the_end() {
+        let mut stream = transformer(json!({}));
+        let mut chunks = stream.push(&gemini(json!([{ "text": "Hel" }]), None));
+        chunks.extend(stream.push(&gemini(json!([{ "text": "lo" }]), Some("STOP"))));
+        chunks.extend(stream.finish().unwrap());
+
+        assert!(chunks.iter().all(|c| c["id"] == chunks[0]["id"] && c["created"] == chunks[0]["created"]));
+        assert!(chunks.iter().all(|c| c["object"] == "chat.completion.chunk" && c.get("usage").is_none()));
+        assert_eq!(chunks[0]["choices"][0]["delta"], json!({ "role": "assistant", "content": "" }));
+        let content: Vec<_> = chunks.iter().filter_map(|c| c["choices"][0]["delta"]["content"].as_str()).collect();
+        assert_eq!(content, ["", "Hel", "lo"]);
+
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| c["choices"][0]["finish_reason"].is_null()));
+        assert_eq!(last["choices"], json!([{ "index": 0, "delta": {}, "finish_reason": "stop" }]));
+    }
+
+    #[test]
+    fn sends_usage_chunk_last_when_requested() {
+        let mut stream = transformer(json!({ "stream_options": { "include_usage": true } }));
+        let mut response = gemini(json!([{ "text": "hi" }]), Some("STOP"));
+        response.usage_metadata = serde_json::from_value(json!({
+            "promptTokenCount": 3, "candidatesTokenCount": 1, "totalTokenCount": 4,
+        })).unwrap();
+        let mut chunks = stream.push(&response);
+        chunks.extend(stream.finish().unwrap());
+
+        let (usage, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| c["usage"].is_null() && c.get("usage").is_some()));
+        assert_eq!(usage["choices"], json!([]));
+        assert_eq!(usage["usage"]["prompt_tokens"], 3);
+        assert_eq!(usage["usage"]["completion_tokens"], 1);
+        assert_eq!(usage["usage"]["total_tokens"], 4);
+    }
+
+    #[test]
+    fn numbers_parallel_tool_calls_and_reports_them_after_a_later_stop() {
+        let mut stream = transformer(json!({}));
+        let mut chunks = stream.push(&gemini(json!([call("a"), call("b")]), None));
+        chunks.extend(stream.push(&gemini(json!([call("c")]), None)));
+        chunks.extend(stream.push(&gemini(json!([{ "text": "" }]), Some("STOP"))));
+        chunks.extend(stream.finish().unwrap());
+
+        assert_eq!(opened_tool_calls(&chunks), [(0, "a".to_string()), (1, "b".to_string()), (2, "c".to_string())]);
+        let arguments: Vec<_> = chunks.iter()
+            .filter_map(|c| c["choices"][0]["delta"]["tool_calls"][0]["function"]["arguments"].as_str())
+            .filter(|a| !a.is_empty())
+            .collect();
+        assert_eq!(arguments, [r#"{"q":"a"}"#, r#"{"q":"b"}"#, r#"{"q":"c"}"#]);
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[test]
+    fn keeps_only_the_first_tool_call_without_parallel_tool_calls() {
+        let mut stream = transformer(json!({ "parallel_tool_calls": false }));
+        let mut chunks = stream.push(&gemini(json!([call("a"), call("b")]), Some("STOP")));
+        chunks.extend(stream.finish().unwrap());
+
+        assert_eq!(opened_tool_calls(&chunks), [(0, "a".to_string())]);
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[test]
+    fn validates_streamed_content_against_a_strict_schema() {
+        let request = json!({ "response_format": { "type": "json_schema", "json_schema": {
+            "name": "answer",
+            "strict": true,
+            "schema": { "type": "object", "properties": { "n": { "type": "integer" } }, "required": ["n"] },
+        } } });
+
+        let mut stream = transformer(request.clone());
+        stream.push(&gemini(json!([{ "text": "{\"n\":" }]), None));
+        stream.push(&gemini(json!([{ "text": "1}" }]), Some("STOP")));
+        assert!(stream.finish().is_ok());
+
+        let mut stream = transformer(request);
+        stream.push(&gemini(json!([{ "text": "{\"m\":1}" }]), Some("STOP")));
+        let error = stream.finish().unwrap_err();
+        assert_eq!(error.code.as_deref(), Some("response_schema_mismatch"));
+        assert!(error.message.ends_with("choice 0: $ is missing required property 'n'"), "{}", error.message);
+    }
+
+    #[test]
+    fn closes_choice_zero_when_upstream_sent_nothing() {
+        let mut stream = transformer(json!({}));
+        let chunks = stream.finish().unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0]["choices"], json!([{ "index": 0, "delta": {}, "finish_reason": "stop" }]));
+    }
+}
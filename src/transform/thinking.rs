@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+/// Gemini `thinkingConfig`. 2.5 models take a token budget, 3.x models a
+/// coarse thinking level.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GeminiThinkingConfig {
+    #[serde(rename = "thinkingBudget", skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<i32>,
+    #[serde(rename = "thinkingLevel", skip_serializing_if = "Option::is_none")]
+    pub thinking_level: Option<String>,
+    #[serde(rename = "includeThoughts", skip_serializing_if = "Option::is_none")]
+    pub include_thoughts: Option<bool>,
+}
+
+/// Extension field for clients that want to set the Gemini thinking
+/// parameters directly. Takes precedence over `reasoning_effort`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OpenAIThinkingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_thoughts: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelFamily {
+    Gemini3Pro,
+    Gemini3Flash,
+    Gemini25Pro,
+    Gemini25Flash,
+    Gemini25FlashLite,
+    Other,
+}
+
+fn model_family(model: &str) -> ModelFamily {
+    let model = model.strip_prefix("models/").unwrap_or(model);
+    if model.starts_with("gemini-3") {
+        if model.contains("flash") {
+            ModelFamily::Gemini3Flash
+        } else {
+            ModelFamily::Gemini3Pro
+        }
+    } else if model.starts_with("gemini-2.5") {
+        if model.contains("flash-lite") {
+            ModelFamily::Gemini25FlashLite
+        } else if model.contains("flash") {
+            ModelFamily::Gemini25Flash
+        } else {
+            ModelFamily::Gemini25Pro
+        }
+    } else {
+        ModelFamily::Other
+    }
+}
+
+pub const REASONING_EFFORTS: &[&str] = &["minimal", "low", "medium", "high"];
+
+/// Maps an OpenAI `reasoning_effort` onto the thinking parameters of the
+/// given model's family. Returns `None` for models without thinking support.
+fn effort_to_config(model: &str, effort: &str) -> Option<GeminiThinkingConfig> {
+    let family = model_family(model);
+    let level = |level: &str| Some(GeminiThinkingConfig {
+        thinking_level: Some(level.to_string()),
+        ..Default::default()
+    });
+    let budget = |budget: i32| Some(GeminiThinkingConfig {
+        thinking_budget: Some(budget),
+        ..Default::default()
+    });
+
+    match (family, effort) {
+        // 3 Pro only distinguishes low and high.
+        (ModelFamily::Gemini3Pro, "minimal" | "low") => level("low"),
+        (ModelFamily::Gemini3Pro, _) => level("high"),
+        (ModelFamily::Gemini3Flash, effort) => level(effort),
+        // 2.5 Pro cannot turn thinking off; 128 is its smallest budget.
+        (ModelFamily::Gemini25Pro, "minimal") => budget(128),
+        (ModelFamily::Gemini25Flash | ModelFamily::Gemini25FlashLite, "minimal") => budget(0),
+        (_, "low") if family != ModelFamily::Other => budget(1024),
+        (_, "medium") if family != ModelFamily::Other => budget(8192),
+        (ModelFamily::Gemini25Pro, "high") => budget(32768),
+        (ModelFamily::Gemini25Flash | ModelFamily::Gemini25FlashLite, "high") => budget(24576),
+        _ => None,
+    }
+}
+
+/// Builds the `thinkingConfig` for a request. Thoughts are returned to the
+/// client unless thinking is disabled or the client opted out explicitly.
+pub fn thinking_config(
+    model: &str,
+    reasoning_effort: Option<&str>,
+    explicit: Option<&OpenAIThinkingConfig>,
+) -> Option<GeminiThinkingConfig> {
+    let mut config = reasoning_effort.and_then(|effort| {
+        let config = effort_to_config(model, effort);
+        if config.is_none() {
+            log::debug!("Ignoring reasoning_effort for model {} without thinking support", model);
+        }
+        config
+    });
+
+    if let Some(explicit) = explicit {
+        let config = config.get_or_insert_with(GeminiThinkingConfig::default);
+        if explicit.thinking_budget.is_some() || explicit.thinking_level.is_some() {
+            config.thinking_budget = explicit.thinking_budget;
+            config.thinking_level = explicit.thinking_level.clone();
+        }
+        config.include_thoughts = explicit.include_thoughts;
+    }
+
+    let mut config = config?;
+    if config.include_thoughts.is_none() {
+        config.include_thoughts = Some(config.thinking_budget != Some(0));
+    }
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn config(model: &str, effort: Option<&str>, explicit: Option<Value>) -> Value {
+        let explicit: Option<OpenAIThinkingConfig> = explicit.map(|e| serde_json::from_value(e).unwrap());
+        json!(thinking_config(model, effort, explicit.as_ref()))
+    }
+
+    #[test]
+    fn maps_reasoning_effort_per_model_family() {
+        let cases = [
+            ("gemini-3-pro-preview", [json!({ "thinkingLevel": "low" }), json!({ "thinkingLevel": "low" }), json!({ "thinkingLevel": "high" }), json!({ "thinkingLevel": "high" })]),
+            ("gemini-3-flash-preview", [json!({ "thinkingLevel": "minimal" }), json!({ "thinkingLevel": "low" }), json!({ "thinkingLevel": "medium" }), json!({ "thinkingLevel": "high" })]),
+            ("gemini-2.5-pro", [json!({ "thinkingBudget": 128 }), json!({ "thinkingBudget": 1024 }), json!({ "thinkingBudget": 8192 }), json!({ "thinkingBudget": 32768 })]),
+            ("models/gemini-2.5-flash", [json!({ "thinkingBudget": 0 }), json!({ "thinkingBudget": 1024 }), json!({ "thinkingBudget": 8192 }), json!({ "thinkingBudget": 24576 })]),
+            ("gemini-2.5-flash-lite", [json!({ "thinkingBudget": 0 }), json!({ "thinkingBudget": 1024 }), json!({ "thinkingBudget": 8192 }), json!({ "thinkingBudget": 24576 })]),
+        ];
+        for (model, expected) in cases {
+            for (effort, mut expected) in REASONING_EFFORTS.iter().zip(expected) {
+                // Thoughts are returned unless thinking is off.
+                expected["includeThoughts"] = json!(expected["thinkingBudget"] != 0);
+                assert_eq!(config(model, Some(effort), None), expected, "{} {}", model, effort);
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_reasoning_effort_for_other_models() {
+        for effort in REASONING_EFFORTS {
+            assert_eq!(config("gemini-2.0-flash", Some(effort), None), Value::Null);
+        }
+        assert_eq!(config("gemini-2.5-pro", None, None), Value::Null);
+    }
+
+    #[test]
+    fn explicit_thinking_config_overrides_reasoning_effort() {
+        assert_eq!(
+            config("gemini-2.5-pro", Some("high"), Some(json!({ "thinking_budget": 2048 }))),
+            json!({ "thinkingBudget": 2048, "includeThoughts": true }),
+        );
+        assert_eq!(
+            config("gemini-3-pro-preview", Some("low"), Some(json!({ "thinking_level": "high" }))),
+            json!({ "thinkingLevel": "high", "includeThoughts": true }),
+        );
+        assert_eq!(
+            config("gemini-2.5-flash", Some("minimal"), Some(json!({ "thinking_budget": 0, "include_thoughts": true }))),
+            json!({ "thinkingBudget": 0, "includeThoughts": true }),
+        );
+    }
+
+    #[test]
+    fn include_thoughts_alone_keeps_the_effort_mapping() {
+        assert_eq!(
+            config("gemini-2.5-flash", Some("medium"), Some(json!({ "include_thoughts": false }))),
+            json!({ "thinkingBudget": 8192, "includeThoughts": false }),
+        );
+        assert_eq!(
+            config("gemini-2.5-flash", None, Some(json!({ "include_thoughts": true }))),
+            json!({ "includeThoughts": true }),
+        );
+    }
+}